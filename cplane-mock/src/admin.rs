@@ -0,0 +1,204 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::{delete, patch, post, put},
+    Json, Router,
+};
+use serde::Deserialize;
+
+use crate::Context;
+
+/// Endpoint changes made through the admin API, layered over the scenario.
+#[derive(Default)]
+pub struct Overrides {
+    endpoints: Mutex<HashMap<String, EndpointOverride>>,
+}
+
+#[derive(Default, Clone)]
+pub struct EndpointOverride {
+    pub access: AccessUpdate,
+    pub deleted: bool,
+}
+
+/// Access control fields to replace. Unset fields keep their current value.
+#[derive(Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessUpdate {
+    pub allowed_ips: Option<Vec<String>>,
+    pub allowed_vpc_endpoint_ids: Option<Vec<String>>,
+    pub block_public_connections: Option<bool>,
+    pub block_vpc_connections: Option<bool>,
+}
+
+impl Overrides {
+    pub fn get(&self, endpoint: &str) -> EndpointOverride {
+        self.endpoints
+            .lock()
+            .unwrap()
+            .get(endpoint)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn update(&self, endpoint: &str, f: impl FnOnce(&mut EndpointOverride)) {
+        f(self
+            .endpoints
+            .lock()
+            .unwrap()
+            .entry(endpoint.to_owned())
+            .or_default());
+    }
+}
+
+/// Routes to change cplane-mock's answers during a run.
+pub fn router() -> Router<Context> {
+    Router::new()
+        .route("/admin/endpoints/:endpoint", delete(delete_endpoint))
+        .route("/admin/endpoints/:endpoint/access", patch(update_access))
+        .route("/admin/endpoints/:endpoint/roles/:role", put(set_password))
+        .route("/admin/endpoints/:endpoint/suspend", post(suspend))
+        .route("/admin/endpoints/:endpoint/migrate", post(migrate))
+        .route("/admin/jwks/rotate", post(rotate_jwks))
+}
+
+#[derive(Deserialize)]
+struct SetPassword {
+    password: String,
+    /// Defaults to the endpoint's iterations.
+    scram_iterations: Option<u32>,
+}
+
+/// Rotates the role's password, or adds the role if the endpoint doesn't have it.
+async fn set_password(
+    state: State<Context>,
+    Path((endpoint, role)): Path<(String, String)>,
+    Json(body): Json<SetPassword>,
+) -> Result<StatusCode, (StatusCode, &'static str)> {
+    println!("admin: set password of role {role} on {endpoint}");
+    if body.scram_iterations == Some(0) {
+        return Err((StatusCode::BAD_REQUEST, "scram_iterations must be positive"));
+    }
+    let iterations = body
+        .scram_iterations
+        .unwrap_or_else(|| state.scenario.endpoint_scram_iterations(&endpoint));
+    state.credentials.set_password(
+        &endpoint,
+        &role,
+        &body.password,
+        iterations,
+        state.scenario.scram_salt(),
+    );
+    if let Some(role_sync) = &state.role_sync {
+        let secret = || {
+            let secret = state.credentials.secret(&state.scenario, &endpoint, &role);
+            secret
+                .map(|secret| (role.clone(), secret))
+                .into_iter()
+                .collect()
+        };
+        role_sync.push(secret).await.map_err(|e| {
+            println!("admin: could not push the password of role {role} to the computes: {e}");
+            (
+                StatusCode::BAD_GATEWAY,
+                "could not push the password to the computes",
+            )
+        })?;
+    }
+    if let Some(notifier) = &state.notifier {
+        notifier.password_updated(&state.scenario.ids(&endpoint).project_id, &role);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn update_access(
+    state: State<Context>,
+    Path(endpoint): Path<String>,
+    Json(update): Json<AccessUpdate>,
+) -> StatusCode {
+    println!("admin: update access control of {endpoint}");
+    let ips_changed = update.allowed_ips.is_some();
+    let vpc_endpoints_changed = update.allowed_vpc_endpoint_ids.is_some();
+    let blocks_changed =
+        update.block_public_connections.is_some() || update.block_vpc_connections.is_some();
+    state.overrides.update(&endpoint, |o| {
+        let access = &mut o.access;
+        access.allowed_ips = update.allowed_ips.or(access.allowed_ips.take());
+        access.allowed_vpc_endpoint_ids = update
+            .allowed_vpc_endpoint_ids
+            .or(access.allowed_vpc_endpoint_ids.take());
+        access.block_public_connections = update
+            .block_public_connections
+            .or(access.block_public_connections);
+        access.block_vpc_connections = update
+            .block_vpc_connections
+            .or(access.block_vpc_connections);
+    });
+    if let Some(notifier) = &state.notifier {
+        let project_id = state.scenario.ids(&endpoint).project_id;
+        if ips_changed {
+            notifier.allowed_ips_updated(&project_id);
+        }
+        if vpc_endpoints_changed {
+            notifier.allowed_vpc_endpoints_updated(&project_id);
+        }
+        if blocks_changed {
+            notifier.block_access_updated(&project_id);
+        }
+    }
+    StatusCode::NO_CONTENT
+}
+
+/// Makes both routes answer ENDPOINT_NOT_FOUND for the endpoint.
+async fn delete_endpoint(state: State<Context>, Path(endpoint): Path<String>) -> StatusCode {
+    println!("admin: delete {endpoint}");
+    state.overrides.update(&endpoint, |o| o.deleted = true);
+    if let Some(notifier) = &state.notifier {
+        notifier.endpoint_settings_updated(&endpoint);
+    }
+    StatusCode::NO_CONTENT
+}
+
+async fn suspend(
+    state: State<Context>,
+    Path(endpoint): Path<String>,
+) -> Result<StatusCode, (StatusCode, &'static str)> {
+    println!("admin: suspend {endpoint}");
+    let computes = state.computes.as_ref().ok_or((
+        StatusCode::CONFLICT,
+        "computes have no state without [lifecycle] in the scenario",
+    ))?;
+    computes.suspend(&endpoint);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct Migrate {
+    address: Option<String>,
+}
+
+/// Moves the endpoint to the given address, or to the next compute in the pool.
+async fn migrate(
+    state: State<Context>,
+    Path(endpoint): Path<String>,
+    body: Option<Json<Migrate>>,
+) -> StatusCode {
+    let address = body.and_then(|Json(body)| body.address);
+    println!("admin: migrate {endpoint} to {address:?}");
+    state.compute_pool.migrate(&endpoint, address);
+    if let Some(notifier) = &state.notifier {
+        notifier.endpoint_settings_updated(&endpoint);
+    }
+    StatusCode::NO_CONTENT
+}
+
+async fn rotate_jwks(state: State<Context>) -> Result<StatusCode, (StatusCode, &'static str)> {
+    let jwks = state
+        .jwks
+        .as_ref()
+        .ok_or((StatusCode::CONFLICT, "no [jwt] in the scenario"))?;
+    let kid = jwks.rotate();
+    println!("admin: rotated JWT signing key to {kid}");
+    Ok(StatusCode::NO_CONTENT)
+}
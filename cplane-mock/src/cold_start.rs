@@ -0,0 +1,123 @@
+use std::time::Duration;
+
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
+
+use crate::{latency::Latency, ColdStartInfo};
+
+/// How wake_compute picks the cold start outcome and how long the wake takes.
+///
+/// Probabilities are relative weights and don't need to add up to 1.
+/// The default is always warm with no added latency.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ColdStart {
+    #[serde(default = "Outcome::always")]
+    pub warm: Outcome,
+    #[serde(default)]
+    pub pool_hit: Outcome,
+    #[serde(default)]
+    pub pool_miss: Outcome,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Outcome {
+    pub probability: f64,
+    #[serde(default)]
+    pub latency: Latency,
+}
+
+impl Outcome {
+    fn always() -> Self {
+        Self {
+            probability: 1.0,
+            latency: Latency::default(),
+        }
+    }
+}
+
+impl Default for ColdStart {
+    fn default() -> Self {
+        Self {
+            warm: Outcome::always(),
+            pool_hit: Outcome::default(),
+            pool_miss: Outcome::default(),
+        }
+    }
+}
+
+impl ColdStart {
+    pub fn validate(&self) -> Result<(), String> {
+        let outcomes = [&self.warm, &self.pool_hit, &self.pool_miss];
+        if outcomes
+            .iter()
+            .any(|o| !o.probability.is_finite() || o.probability < 0.0)
+        {
+            return Err("cold start probabilities must be non-negative".to_owned());
+        }
+        let total = outcomes.iter().map(|o| o.probability).sum::<f64>();
+        if !(total.is_finite() && total > 0.0) {
+            return Err(format!(
+                "cold start probabilities must add up to a positive finite number, not {total}"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Picks an outcome by weight and samples its latency.
+    pub fn sample(&self) -> (ColdStartInfo, Duration) {
+        let total = self.warm.probability + self.pool_hit.probability + self.pool_miss.probability;
+        let mut x = thread_rng().gen_range(0.0..total);
+
+        for (info, outcome) in [
+            (ColdStartInfo::Warm, &self.warm),
+            (ColdStartInfo::VmPoolHit, &self.pool_hit),
+            (ColdStartInfo::VmPoolMiss, &self.pool_miss),
+        ] {
+            if x < outcome.probability {
+                return (info, outcome.latency.sample());
+            }
+            x -= outcome.probability;
+        }
+
+        // only reachable through float rounding
+        (ColdStartInfo::Warm, self.warm.latency.sample())
+    }
+
+    /// Like [`ColdStart::sample`], for a compute known to be suspended. Ignores the warm weight.
+    pub fn sample_cold(&self) -> (ColdStartInfo, Duration) {
+        let total = self.pool_hit.probability + self.pool_miss.probability;
+        if total > 0.0 && thread_rng().gen_range(0.0..total) >= self.pool_hit.probability {
+            (ColdStartInfo::VmPoolMiss, self.pool_miss.latency.sample())
+        } else {
+            (ColdStartInfo::VmPoolHit, self.pool_hit.latency.sample())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn weights(warm: f64, pool_hit: f64, pool_miss: f64) -> ColdStart {
+        let outcome = |probability| Outcome {
+            probability,
+            latency: Latency::default(),
+        };
+        ColdStart {
+            warm: outcome(warm),
+            pool_hit: outcome(pool_hit),
+            pool_miss: outcome(pool_miss),
+        }
+    }
+
+    #[test]
+    fn validate() {
+        assert!(weights(1.0, 0.0, 0.0).validate().is_ok());
+        assert!(weights(0.0, 0.0, 0.0).validate().is_err());
+        assert!(weights(-1.0, 1.0, 1.0).validate().is_err());
+        assert!(weights(f64::NAN, 1.0, 0.0).validate().is_err());
+        assert!(weights(f64::MAX, f64::MAX, 0.0).validate().is_err());
+    }
+}
@@ -0,0 +1,305 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::Deserialize;
+use tokio::time::Instant;
+
+use crate::{cold_start::ColdStart, scenario::glob_match, ColdStartInfo};
+
+/// Moves matching endpoints to the next compute in the pool `after_secs` into the run,
+/// and again every `every_secs` after that if set. Each move also changes the compute id.
+///
+/// ```toml
+/// [[migrations]]
+/// endpoint = "ep-hello-world-1*"
+/// after_secs = 120
+/// every_secs = 60
+/// ```
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Migration {
+    pub endpoint: String,
+    pub after_secs: u64,
+    pub every_secs: Option<u64>,
+}
+
+impl Migration {
+    /// How many times this rule has moved the endpoint so far.
+    fn moves(&self, endpoint: &str, elapsed: Duration) -> u64 {
+        let elapsed = elapsed.as_secs();
+        if elapsed < self.after_secs || !glob_match(self.endpoint.as_bytes(), endpoint.as_bytes()) {
+            return 0;
+        }
+        1 + self
+            .every_secs
+            .filter(|&every| every > 0)
+            .map_or(0, |every| (elapsed - self.after_secs) / every)
+    }
+}
+
+/// The computes endpoints are assigned to, from the comma separated `$PROXY_COMPUTE_ADDR`.
+pub struct ComputePool {
+    addresses: Vec<String>,
+    /// From `$COMPUTE_DOMAIN`. When set, computes are addressed as `{compute_id}.{domain}` on
+    /// the port of their pool address, for dns-mock to resolve.
+    domain: Option<String>,
+    started: Instant,
+    /// Migrations requested through the admin API.
+    moved: Mutex<HashMap<String, Moved>>,
+}
+
+#[derive(Default)]
+struct Moved {
+    moves: u64,
+    /// Pins the endpoint to an address outside the rotation.
+    address: Option<String>,
+}
+
+/// Where an endpoint's compute currently lives.
+pub struct Assignment {
+    pub address: String,
+    pub compute_id: String,
+}
+
+impl ComputePool {
+    pub fn new(addresses: &str, domain: Option<String>) -> Self {
+        let addresses: Vec<_> = addresses
+            .split(',')
+            .map(str::trim)
+            .filter(|addr| !addr.is_empty())
+            .map(str::to_owned)
+            .collect();
+        assert!(!addresses.is_empty(), "PROXY_COMPUTE_ADDR has no addresses");
+        println!("Compute pool: {}", addresses.join(", "));
+        let domain = domain.map(|domain| domain.trim_matches('.').to_owned());
+        if let Some(domain) = &domain {
+            let hosts: Vec<_> = addresses
+                .iter()
+                .map(|addr| {
+                    let (host, _) = addr
+                        .rsplit_once(':')
+                        .expect("PROXY_COMPUTE_ADDR addresses need a port to use COMPUTE_DOMAIN");
+                    host
+                })
+                .collect();
+            // dns-mock resolves every name to the same address, so only the port tells computes apart
+            assert!(
+                hosts.iter().all(|host| *host == hosts[0]),
+                "PROXY_COMPUTE_ADDR addresses must share one host to use COMPUTE_DOMAIN"
+            );
+            println!("Compute hostnames: compute-<id>.{domain}");
+        }
+
+        Self {
+            addresses,
+            domain,
+            started: Instant::now(),
+            moved: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn addresses(&self) -> &[String] {
+        &self.addresses
+    }
+
+    /// Moves the endpoint to `address`, or to the next compute in the pool, with a new compute id.
+    pub fn migrate(&self, endpoint: &str, address: Option<String>) {
+        let mut moved = self.moved.lock().unwrap();
+        let moved = moved.entry(endpoint.to_owned()).or_default();
+        moved.moves += 1;
+        moved.address = address;
+    }
+
+    /// Picks a compute by hashing the endpoint id, so an endpoint stays on one compute until it migrates.
+    pub fn assign(&self, endpoint: &str, migrations: &[Migration]) -> Assignment {
+        let elapsed = self.started.elapsed();
+        let mut moves: u64 = migrations.iter().map(|m| m.moves(endpoint, elapsed)).sum();
+        let mut pinned = None;
+        if let Some(moved) = self.moved.lock().unwrap().get(endpoint) {
+            moves += moved.moves;
+            pinned = moved.address.clone();
+        }
+
+        let n = self.addresses.len() as u64;
+        let slot = (stable_hash(endpoint) % n + moves % n) % n;
+        let compute_id = format!("compute-{:016x}", stable_hash((endpoint, moves)));
+        let address = pinned.unwrap_or_else(|| {
+            let address = &self.addresses[slot as usize];
+            match &self.domain {
+                Some(domain) => {
+                    let (_, port) = address.rsplit_once(':').unwrap();
+                    format!("{compute_id}.{domain}:{port}")
+                }
+                None => address.clone(),
+            }
+        });
+        Assignment {
+            address,
+            compute_id,
+        }
+    }
+}
+
+fn stable_hash(value: impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Enables per-endpoint compute state, so only a wake of a suspended compute is a cold start.
+///
+/// ```toml
+/// [lifecycle]
+/// idle_timeout_secs = 300
+/// ```
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Lifecycle {
+    /// A running compute suspends once it hasn't been woken for this long.
+    pub idle_timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ComputeState {
+    Suspended,
+    Starting,
+    Running { last_active: Instant },
+}
+
+struct Endpoint {
+    /// Held for the whole wake, so concurrent wakes of one endpoint queue up like in the control plane.
+    wake_lock: tokio::sync::Mutex<()>,
+    state: Mutex<ComputeState>,
+}
+
+/// In-memory compute state of every endpoint that has been woken so far.
+pub struct Computes {
+    idle_timeout: Duration,
+    endpoints: Mutex<HashMap<String, Arc<Endpoint>>>,
+}
+
+impl Computes {
+    pub fn new(lifecycle: &Lifecycle) -> Self {
+        Self {
+            idle_timeout: Duration::from_secs(lifecycle.idle_timeout_secs),
+            endpoints: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn endpoint(&self, endpoint: &str) -> Arc<Endpoint> {
+        self.endpoints
+            .lock()
+            .unwrap()
+            .entry(endpoint.to_owned())
+            .or_insert_with(|| {
+                Arc::new(Endpoint {
+                    wake_lock: tokio::sync::Mutex::new(()),
+                    state: Mutex::new(ComputeState::Suspended),
+                })
+            })
+            .clone()
+    }
+
+    /// Suspends the compute, so the next wake is a cold start.
+    pub fn suspend(&self, endpoint: &str) {
+        *self.endpoint(endpoint).state.lock().unwrap() = ComputeState::Suspended;
+    }
+
+    /// Wakes the endpoint's compute, starting it if it is suspended.
+    ///
+    /// Returns once the compute is running, like the control plane does.
+    pub async fn wake(&self, endpoint: &str, cold_start: &ColdStart) -> ColdStartInfo {
+        let ep = self.endpoint(endpoint);
+        let guard = ep.wake_lock.lock().await;
+
+        let now = Instant::now();
+        let running = {
+            let mut state = ep.state.lock().unwrap();
+            match *state {
+                ComputeState::Running { last_active } if now - last_active < self.idle_timeout => {
+                    *state = ComputeState::Running { last_active: now };
+                    true
+                }
+                _ => {
+                    *state = ComputeState::Starting;
+                    false
+                }
+            }
+        };
+
+        if running {
+            drop(guard);
+            tokio::time::sleep(cold_start.warm.latency.sample()).await;
+            return ColdStartInfo::Warm;
+        }
+
+        let (info, latency) = cold_start.sample_cold();
+        tokio::time::sleep(latency).await;
+        let mut state = ep.state.lock().unwrap();
+        // a suspend during the start wins, so the next wake is cold again
+        if let ComputeState::Starting = *state {
+            *state = ComputeState::Running {
+                last_active: Instant::now(),
+            };
+        }
+        info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cold_start::Outcome, latency::Latency};
+
+    use super::*;
+
+    fn cold_start(ms: u64) -> ColdStart {
+        ColdStart {
+            pool_hit: Outcome {
+                probability: 1.0,
+                latency: Latency::Fixed(Duration::from_millis(ms)),
+            },
+            ..ColdStart::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn suspend_during_cold_wake() {
+        let computes = Arc::new(Computes::new(&Lifecycle {
+            idle_timeout_secs: 300,
+        }));
+        assert!(matches!(
+            computes.wake("ep", &cold_start(0)).await,
+            ColdStartInfo::VmPoolHit
+        ));
+        assert!(matches!(
+            computes.wake("ep", &cold_start(0)).await,
+            ColdStartInfo::Warm
+        ));
+
+        computes.suspend("ep");
+        let wake = tokio::spawn({
+            let computes = computes.clone();
+            async move { computes.wake("ep", &cold_start(100)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(matches!(
+            *computes.endpoint("ep").state.lock().unwrap(),
+            ComputeState::Starting
+        ));
+        computes.suspend("ep");
+        assert!(matches!(wake.await.unwrap(), ColdStartInfo::VmPoolHit));
+
+        assert!(matches!(
+            *computes.endpoint("ep").state.lock().unwrap(),
+            ComputeState::Suspended
+        ));
+        assert!(matches!(
+            computes.wake("ep", &cold_start(0)).await,
+            ColdStartInfo::VmPoolHit
+        ));
+    }
+}
@@ -0,0 +1,227 @@
+use std::error::Error;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{latency::Latency, ColdStartInfo, Context, MetricsAuxInfo};
+
+/// How sessions of the proxy's console redirect auth backend get approved.
+///
+/// The proxy sends the client a link to `/console-redirect/{session_id}`. Opening it approves
+/// the session: after `approve_after`, cplane-mock connects to the proxy's management
+/// interface at `$PROXY_MGMT_ADDR` and hands it the compute to connect to.
+///
+/// ```toml
+/// [console_redirect]
+/// endpoint = "ep-console-redirect"
+/// approve_after = { distribution = "normal", mean_ms = 2000, std_dev_ms = 500 }
+/// fail_percent = 1
+/// ```
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConsoleRedirect {
+    /// Endpoint whose compute the sessions are sent to.
+    #[serde(default = "ConsoleRedirect::default_endpoint")]
+    pub endpoint: String,
+    #[serde(default)]
+    pub approve_after: Latency,
+    #[serde(default = "ConsoleRedirect::default_dbname")]
+    pub dbname: String,
+    #[serde(default = "ConsoleRedirect::default_user")]
+    pub user: String,
+    #[serde(default = "ConsoleRedirect::default_password")]
+    pub password: String,
+    /// Sessions sent to an unreachable compute, so the proxy fails to connect and rejects the
+    /// client. The proxy has no way to reject a session outright.
+    #[serde(default)]
+    pub fail_percent: f64,
+}
+
+impl ConsoleRedirect {
+    fn default_endpoint() -> String {
+        "ep-console-redirect".to_owned()
+    }
+
+    fn default_dbname() -> String {
+        "postgres".to_owned()
+    }
+
+    fn default_user() -> String {
+        "postgres".to_owned()
+    }
+
+    fn default_password() -> String {
+        "password".to_owned()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=100.0).contains(&self.fail_percent) {
+            return Err(format!(
+                "console redirect fail_percent {} is not within 0..=100",
+                self.fail_percent
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for ConsoleRedirect {
+    fn default() -> Self {
+        Self {
+            endpoint: Self::default_endpoint(),
+            approve_after: Latency::default(),
+            dbname: Self::default_dbname(),
+            user: Self::default_user(),
+            password: Self::default_password(),
+            fail_percent: 0.0,
+        }
+    }
+}
+
+/// Nothing listens on tcpmux's port, so connections to it are refused.
+const UNREACHABLE_COMPUTE: (&str, u16) = ("127.0.0.1", 1);
+
+/// Message the proxy's management interface expects, as the text of a simple query.
+#[derive(Serialize)]
+struct KickSession<'a> {
+    session_id: &'a str,
+    result: KickResult,
+}
+
+/// The proxy only accepts `Success`.
+#[derive(Serialize)]
+enum KickResult {
+    Success(DatabaseInfo),
+}
+
+#[derive(Serialize)]
+struct DatabaseInfo {
+    host: String,
+    port: u16,
+    dbname: String,
+    user: String,
+    password: Option<String>,
+    aux: MetricsAuxInfo,
+}
+
+/// The link the proxy sends to clients. Approves the session in the background.
+pub async fn open_link(
+    state: State<Context>,
+    Path(session_id): Path<String>,
+) -> Result<&'static str, (StatusCode, &'static str)> {
+    println!("console_redirect: session {session_id} opened");
+    let mgmt_addr = std::env::var("PROXY_MGMT_ADDR").map_err(|_| {
+        (
+            StatusCode::CONFLICT,
+            "set PROXY_MGMT_ADDR to approve console redirect sessions",
+        )
+    })?;
+
+    tokio::spawn(async move {
+        let config = &state.scenario.console_redirect;
+        tokio::time::sleep(config.approve_after.sample()).await;
+        let mut info = match database_info(&state) {
+            Ok(info) => info,
+            Err(e) => {
+                println!("console_redirect: could not approve session {session_id}: {e}");
+                return;
+            }
+        };
+        if thread_rng().gen_bool(config.fail_percent / 100.0) {
+            println!("console_redirect: sending session {session_id} to an unreachable compute");
+            (info.host, info.port) = (UNREACHABLE_COMPUTE.0.to_owned(), UNREACHABLE_COMPUTE.1);
+        }
+        let kick = KickSession {
+            session_id: &session_id,
+            result: KickResult::Success(info),
+        };
+        match kick_session(&mgmt_addr, &serde_json::to_string(&kick).unwrap()).await {
+            Ok(()) => println!("console_redirect: session {session_id} kicked"),
+            Err(e) => println!("console_redirect: could not kick session {session_id}: {e}"),
+        }
+    });
+    Ok("Approving session, you can close this page.\n")
+}
+
+fn database_info(state: &Context) -> Result<DatabaseInfo, String> {
+    let config = &state.scenario.console_redirect;
+    let ids = state.scenario.ids(&config.endpoint);
+    let compute = state
+        .compute_pool
+        .assign(&config.endpoint, &state.scenario.migrations);
+    let address = &compute.address;
+    let (host, port) = address
+        .rsplit_once(':')
+        .ok_or_else(|| format!("compute address {address} is not host:port"))?;
+    let port = port
+        .parse()
+        .map_err(|e| format!("compute address {address} has an invalid port: {e}"))?;
+
+    Ok(DatabaseInfo {
+        host: host.to_owned(),
+        port,
+        dbname: config.dbname.clone(),
+        user: config.user.clone(),
+        password: Some(config.password.clone()),
+        aux: MetricsAuxInfo {
+            endpoint_id: config.endpoint.clone(),
+            project_id: ids.project_id,
+            branch_id: ids.branch_id,
+            compute_id: compute.compute_id,
+            cold_start_info: ColdStartInfo::Warm,
+        },
+    })
+}
+
+/// Sends the message as a simple query over the postgres protocol, like the control plane does.
+async fn kick_session(addr: &str, message: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut s = TcpStream::connect(addr).await?;
+
+    let mut startup = Vec::new();
+    startup.extend_from_slice(&196608u32.to_be_bytes());
+    startup.extend_from_slice(b"user\0cplane-mock\0\0");
+    s.write_all(&((startup.len() + 4) as u32).to_be_bytes())
+        .await?;
+    s.write_all(&startup).await?;
+    read_until_ready(&mut s).await?;
+
+    let mut query = vec![b'Q'];
+    query.extend_from_slice(&((message.len() + 5) as u32).to_be_bytes());
+    query.extend_from_slice(message.as_bytes());
+    query.push(0);
+    s.write_all(&query).await?;
+    read_until_ready(&mut s).await?;
+
+    s.write_all(&[b'X', 0, 0, 0, 4]).await?;
+    Ok(())
+}
+
+/// Skips backend messages up to ReadyForQuery, failing on ErrorResponse.
+async fn read_until_ready(s: &mut TcpStream) -> Result<(), Box<dyn Error + Send + Sync>> {
+    loop {
+        let tag = s.read_u8().await?;
+        let len = s.read_u32().await? as usize;
+        let mut body = vec![0; len.saturating_sub(4)];
+        s.read_exact(&mut body).await?;
+        match tag {
+            b'Z' => return Ok(()),
+            b'E' => {
+                // fields are a type byte and a C string, 'M' is the message
+                let message = body
+                    .split(|&b| b == 0)
+                    .find_map(|field| field.strip_prefix(b"M"))
+                    .unwrap_or_default();
+                return Err(String::from_utf8_lossy(message).into());
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,336 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::Mutex,
+};
+
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use rand::{thread_rng, RngCore};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::scenario::Scenario;
+
+/// Password used for every role of endpoints whose scenario rule doesn't list roles.
+const DEFAULT_PASSWORD: &str = "password";
+
+/// Iterations used when neither the scenario nor `$CPLANE_SCRAM_ITERATIONS` set them, like Postgres.
+pub const DEFAULT_SCRAM_ITERATIONS: u32 = 4096;
+
+/// A role's password, optionally with its own SCRAM parameters or another kind of secret.
+///
+/// ```toml
+/// roles = { demo = "password", slow = { password = "hunter2", scram_iterations = 100000 } }
+/// ```
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum RoleConfig {
+    Password(String),
+    Detailed(RoleDetails),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoleDetails {
+    #[serde(default)]
+    pub secret: SecretKind,
+    /// Required unless the role has no secret.
+    pub password: Option<String>,
+    /// Defaults to the endpoint rule's iterations.
+    pub scram_iterations: Option<u32>,
+    /// Base64 salt, defaults to the scenario's `scram_salt`, or a random one.
+    pub salt: Option<String>,
+}
+
+/// What the control plane stores for a role.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SecretKind {
+    #[default]
+    Scram,
+    /// Legacy `md5<hex>` secret, without a SCRAM one.
+    Md5,
+    /// The role exists but has no password, e.g. it only logs in with JWTs.
+    None,
+}
+
+/// A role's secret as the control plane returns it.
+#[derive(Clone, PartialEq)]
+pub enum Secret {
+    Scram(String),
+    Md5(String),
+    None,
+}
+
+impl Secret {
+    /// The `role_secret` field, which is empty for roles without a password.
+    pub fn role_secret(&self) -> String {
+        match self {
+            Self::Scram(secret) | Self::Md5(secret) => secret.clone(),
+            Self::None => String::new(),
+        }
+    }
+
+    pub fn scram_secret(&self) -> Option<String> {
+        match self {
+            Self::Scram(secret) => Some(secret.clone()),
+            Self::Md5(_) | Self::None => None,
+        }
+    }
+}
+
+impl RoleConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let Self::Detailed(role) = self else {
+            return Ok(());
+        };
+        match (role.secret, &role.password) {
+            (SecretKind::None, Some(_)) => {
+                return Err("a role without a secret can't have a password".to_owned())
+            }
+            (SecretKind::Scram | SecretKind::Md5, None) => {
+                return Err("password is required".to_owned())
+            }
+            _ => {}
+        }
+        if role.secret != SecretKind::Scram
+            && (role.scram_iterations.is_some() || role.salt.is_some())
+        {
+            return Err("scram_iterations and salt only apply to SCRAM secrets".to_owned());
+        }
+        if role.scram_iterations == Some(0) {
+            return Err("scram_iterations must be positive".to_owned());
+        }
+        if let Some(salt) = &role.salt {
+            validate_salt(salt)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the role sets its own SCRAM iterations or salt.
+    pub fn has_scram_params(&self) -> bool {
+        matches!(self, Self::Detailed(role) if role.scram_iterations.is_some() || role.salt.is_some())
+    }
+
+    /// `role` is the role name, which MD5 secrets are salted with. `salt` applies to SCRAM
+    /// secrets that don't set their own.
+    fn secret(&self, role: &str, iterations: u32, salt: Option<&str>) -> Secret {
+        match self {
+            Self::Password(password) => Secret::Scram(scram_secret(password, iterations, salt)),
+            Self::Detailed(details) => {
+                // validated to be set for these kinds
+                let password = details.password.as_deref().unwrap_or_default();
+                match details.secret {
+                    SecretKind::Scram => Secret::Scram(scram_secret(
+                        password,
+                        details.scram_iterations.unwrap_or(iterations),
+                        details.salt.as_deref().or(salt),
+                    )),
+                    SecretKind::Md5 => Secret::Md5(md5_secret(password, role)),
+                    SecretKind::None => Secret::None,
+                }
+            }
+        }
+    }
+}
+
+/// SCRAM secrets for every (endpoint rule, role) pair, derived once at startup.
+pub struct CredentialStore {
+    /// Secret for [`DEFAULT_PASSWORD`], shared by all roles of endpoints without a matching rule.
+    default: Secret,
+    /// Indexed like [`Scenario::endpoints`].
+    rules: Vec<RuleSecrets>,
+    /// Passwords set through the admin API, by (endpoint, role).
+    overrides: Mutex<HashMap<(String, String), Secret>>,
+}
+
+enum RuleSecrets {
+    /// Any role is accepted with [`DEFAULT_PASSWORD`].
+    AnyRole(Secret),
+    Roles(HashMap<String, Secret>),
+}
+
+impl CredentialStore {
+    pub fn new(scenario: &Scenario) -> Self {
+        let salt = scenario.scram_salt();
+        let rules: Vec<_> = scenario
+            .endpoints
+            .iter()
+            .map(|rule| {
+                let iterations = rule
+                    .scram_iterations
+                    .unwrap_or_else(|| scenario.scram_iterations());
+                let Some(roles) = &rule.roles else {
+                    return RuleSecrets::AnyRole(Secret::Scram(scram_secret(
+                        DEFAULT_PASSWORD,
+                        iterations,
+                        salt,
+                    )));
+                };
+                let roles = roles
+                    .iter()
+                    .map(|(name, role)| (name.clone(), role.secret(name, iterations, salt)))
+                    .collect();
+                RuleSecrets::Roles(roles)
+            })
+            .collect();
+
+        let roles: usize = rules
+            .iter()
+            .map(|rule| match rule {
+                RuleSecrets::AnyRole(_) => 0,
+                RuleSecrets::Roles(roles) => roles.len(),
+            })
+            .sum();
+        println!(
+            "Generated SCRAM secrets for {roles} roles, {} iterations by default",
+            scenario.scram_iterations()
+        );
+        // a compute holds one secret per role, whichever endpoint it serves
+        let mut first = HashMap::new();
+        let mut conflicts = BTreeSet::new();
+        for rule in &rules {
+            if let RuleSecrets::Roles(roles) = rule {
+                for (name, secret) in roles {
+                    if first.entry(name).or_insert(secret) != &secret {
+                        conflicts.insert(name.as_str());
+                    }
+                }
+            }
+        }
+        if !conflicts.is_empty() {
+            println!(
+                "Roles {conflicts:?} have different secrets in several endpoint rules, computes only get the first one"
+            );
+        }
+
+        Self {
+            default: Secret::Scram(scram_secret(
+                DEFAULT_PASSWORD,
+                scenario.scram_iterations(),
+                salt,
+            )),
+            rules,
+            overrides: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the secret for the role, or `None` if the endpoint has no such role.
+    pub fn secret(&self, scenario: &Scenario, endpoint: &str, role: &str) -> Option<Secret> {
+        let overrides = self.overrides.lock().unwrap();
+        if let Some(secret) = overrides.get(&(endpoint.to_owned(), role.to_owned())) {
+            return Some(secret.clone());
+        }
+        drop(overrides);
+
+        match scenario.endpoint_index(endpoint).map(|i| &self.rules[i]) {
+            Some(RuleSecrets::Roles(roles)) => roles.get(role).cloned(),
+            Some(RuleSecrets::AnyRole(secret)) => Some(secret.clone()),
+            None => Some(self.default.clone()),
+        }
+    }
+
+    /// The secret of every role the scenario lists or the admin API set, for the computes.
+    /// The first rule listing a role wins, and passwords set through the admin API win over both.
+    pub fn roles(&self) -> Vec<(String, Secret)> {
+        let mut secrets = BTreeMap::new();
+        for rule in self.rules.iter().rev() {
+            if let RuleSecrets::Roles(roles) = rule {
+                secrets.extend(roles.clone());
+            }
+        }
+        let overrides = self.overrides.lock().unwrap();
+        secrets.extend(
+            overrides
+                .iter()
+                .map(|((_, role), secret)| (role.clone(), secret.clone())),
+        );
+        secrets.into_iter().collect()
+    }
+
+    /// Replaces the role's secret on this one endpoint, adding the role if needed.
+    pub fn set_password(
+        &self,
+        endpoint: &str,
+        role: &str,
+        password: &str,
+        iterations: u32,
+        salt: Option<&str>,
+    ) {
+        let secret = Secret::Scram(scram_secret(password, iterations, salt));
+        self.overrides
+            .lock()
+            .unwrap()
+            .insert((endpoint.to_owned(), role.to_owned()), secret);
+    }
+}
+
+/// Checks a base64 SCRAM salt from the scenario.
+pub fn validate_salt(salt: &str) -> Result<(), String> {
+    let decoded = base64::decode(salt).map_err(|e| format!("invalid salt {salt}: {e}"))?;
+    if decoded.is_empty() {
+        return Err("salt must not be empty".to_owned());
+    }
+    Ok(())
+}
+
+/// Derives a `SCRAM-SHA-256$<iterations>:<salt>$<StoredKey>:<ServerKey>` secret.
+///
+/// `salt` is base64 and already validated. A random one is used if it's unset.
+fn scram_secret(password: &str, iterations: u32, salt: Option<&str>) -> String {
+    let salt = match salt {
+        Some(salt) => base64::decode(salt).unwrap(),
+        None => {
+            let mut salt = vec![0; 16];
+            thread_rng().fill_bytes(&mut salt);
+            salt
+        }
+    };
+
+    let mut salted_password = [0; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, iterations, &mut salted_password);
+
+    let client_key = hmac_sha256(&salted_password, b"Client Key");
+    let stored_key = Sha256::digest(client_key);
+    let server_key = hmac_sha256(&salted_password, b"Server Key");
+
+    format!(
+        "SCRAM-SHA-256${iterations}:{}${}:{}",
+        base64::encode(salt),
+        base64::encode(stored_key),
+        base64::encode(server_key),
+    )
+}
+
+/// Derives Postgres' legacy `md5<hex>` secret, the MD5 of the password followed by the role name.
+fn md5_secret(password: &str, role: &str) -> String {
+    let digest = Md5::new()
+        .chain_update(password)
+        .chain_update(role)
+        .finalize();
+    let hex: String = digest.iter().map(|b| format!("{b:02x}")).collect();
+    format!("md5{hex}")
+}
+
+fn hmac_sha256(key: &[u8], msg: &[u8]) -> [u8; 32] {
+    Hmac::<Sha256>::new_from_slice(key)
+        .unwrap()
+        .chain_update(msg)
+        .finalize()
+        .into_bytes()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scram_secret_matches_postgres_mock() {
+        // postgres-mock's SCRAM tests verify exchanges against this secret
+        assert_eq!(
+            scram_secret("hunter2", 4096, Some("c2FsdHNhbHRzYWx0c2FsdA==")),
+            "SCRAM-SHA-256$4096:c2FsdHNhbHRzYWx0c2FsdA==$q8t7J+WVNsRr6Tu06Ubd5gdShr+OmOy+x7A3A22eiFI=:yo5XYL09RIjhOLG0BUQ1/Yy5T0QPImxAWUz2IYFjEsk="
+        );
+    }
+}
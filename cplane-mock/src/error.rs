@@ -0,0 +1,252 @@
+use std::time::Duration;
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::scenario::{glob_match, Route};
+
+/// Error body in the shape the proxy's control plane client parses.
+#[derive(Serialize)]
+pub struct ControlPlaneError {
+    #[serde(skip)]
+    http_status_code: StatusCode,
+    error: String,
+    status: Option<Status>,
+}
+
+#[derive(Serialize)]
+struct Status {
+    code: &'static str,
+    message: String,
+    details: Details,
+}
+
+#[derive(Serialize)]
+struct Details {
+    error_info: Option<ErrorInfo>,
+    retry_info: Option<RetryInfo>,
+    user_facing_message: Option<UserFacingMessage>,
+}
+
+#[derive(Serialize)]
+struct ErrorInfo {
+    reason: Reason,
+}
+
+#[derive(Serialize)]
+struct RetryInfo {
+    retry_delay_ms: u64,
+}
+
+#[derive(Serialize)]
+struct UserFacingMessage {
+    message: String,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Reason {
+    RoleProtected,
+    /// The proxy treats this as "no secret" and lets the password check fail.
+    ResourceNotFound,
+    ProjectNotFound,
+    EndpointNotFound,
+    BranchNotFound,
+    EndpointDisabled,
+    ProjectDisabled,
+    RateLimitExceeded,
+    QuotaExceeded,
+    #[serde(rename = "NON_PRIMARY_BRANCH_COMPUTE_TIME_EXCEEDED")]
+    NonDefaultBranchComputeTimeExceeded,
+    ActiveTimeQuotaExceeded,
+    ComputeTimeQuotaExceeded,
+    WrittenDataQuotaExceeded,
+    DataTransferQuotaExceeded,
+    LogicalSizeQuotaExceeded,
+    RunningOperations,
+    ConcurrencyLimitReached,
+    LockAlreadyTaken,
+}
+
+impl Reason {
+    fn default_status(self) -> StatusCode {
+        match self {
+            Reason::ResourceNotFound
+            | Reason::ProjectNotFound
+            | Reason::EndpointNotFound
+            | Reason::BranchNotFound => StatusCode::NOT_FOUND,
+            Reason::RoleProtected | Reason::EndpointDisabled | Reason::ProjectDisabled => {
+                StatusCode::FORBIDDEN
+            }
+            Reason::RateLimitExceeded | Reason::ConcurrencyLimitReached => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            Reason::QuotaExceeded
+            | Reason::NonDefaultBranchComputeTimeExceeded
+            | Reason::ActiveTimeQuotaExceeded
+            | Reason::ComputeTimeQuotaExceeded
+            | Reason::WrittenDataQuotaExceeded
+            | Reason::DataTransferQuotaExceeded
+            | Reason::LogicalSizeQuotaExceeded => StatusCode::LOCKED,
+            Reason::RunningOperations | Reason::LockAlreadyTaken => StatusCode::CONFLICT,
+        }
+    }
+}
+
+impl ControlPlaneError {
+    pub fn new(http_status_code: StatusCode, reason: Reason, message: impl Into<String>) -> Self {
+        let message = message.into();
+        Self {
+            http_status_code,
+            error: message.clone(),
+            status: Some(Status {
+                code: grpc_code(http_status_code),
+                message: message.clone(),
+                details: Details {
+                    error_info: Some(ErrorInfo { reason }),
+                    retry_info: None,
+                    user_facing_message: Some(UserFacingMessage { message }),
+                },
+            }),
+        }
+    }
+
+    pub fn http_status_code(&self) -> StatusCode {
+        self.http_status_code
+    }
+
+    pub fn role_not_found() -> Self {
+        Self::new(
+            StatusCode::NOT_FOUND,
+            Reason::ResourceNotFound,
+            "role not found",
+        )
+    }
+
+    pub fn endpoint_not_found() -> Self {
+        Self::new(
+            StatusCode::NOT_FOUND,
+            Reason::EndpointNotFound,
+            "endpoint not found",
+        )
+    }
+
+    pub fn too_many_requests(reason: Reason, retry_after: Duration) -> Self {
+        Self::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            reason,
+            "too many requests, please retry later",
+        )
+        .with_retry_delay(Some(retry_after.as_millis() as u64))
+    }
+
+    fn with_retry_delay(mut self, retry_delay_ms: Option<u64>) -> Self {
+        if let Some(status) = &mut self.status {
+            status.details.retry_info =
+                retry_delay_ms.map(|retry_delay_ms| RetryInfo { retry_delay_ms });
+        }
+        self
+    }
+}
+
+impl IntoResponse for ControlPlaneError {
+    fn into_response(self) -> Response {
+        (self.http_status_code, Json(self)).into_response()
+    }
+}
+
+/// Maps the HTTP status onto the gRPC-style code the control plane puts in `status.code`.
+fn grpc_code(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::BAD_REQUEST => "INVALID_ARGUMENT",
+        StatusCode::UNAUTHORIZED => "UNAUTHENTICATED",
+        StatusCode::FORBIDDEN => "PERMISSION_DENIED",
+        StatusCode::NOT_FOUND => "NOT_FOUND",
+        StatusCode::CONFLICT => "ABORTED",
+        StatusCode::LOCKED => "FAILED_PRECONDITION",
+        StatusCode::TOO_MANY_REQUESTS => "RESOURCE_EXHAUSTED",
+        StatusCode::SERVICE_UNAVAILABLE => "UNAVAILABLE",
+        StatusCode::GATEWAY_TIMEOUT => "DEADLINE_EXCEEDED",
+        _ => "INTERNAL",
+    }
+}
+
+/// Makes a route fail for some or all requests of matching endpoints.
+///
+/// ```toml
+/// [[errors]]
+/// route = "wake_compute"
+/// endpoint = "ep-flaky-*"
+/// percent = 10
+/// reason = "RUNNING_OPERATIONS"
+/// retry_delay_ms = 500
+/// ```
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ErrorRule {
+    /// Both routes if unset.
+    pub route: Option<Route>,
+    /// Endpoint id or glob, all endpoints if unset.
+    pub endpoint: Option<String>,
+    #[serde(default = "ErrorRule::always")]
+    pub percent: f64,
+    pub reason: Reason,
+    /// Defaults to the status the control plane uses for the reason. Must be a 4xx or 5xx.
+    pub status: Option<u16>,
+    pub message: Option<String>,
+    pub retry_delay_ms: Option<u64>,
+}
+
+impl ErrorRule {
+    fn always() -> f64 {
+        100.0
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=100.0).contains(&self.percent) {
+            return Err(format!(
+                "error percent {} is not within 0..=100",
+                self.percent
+            ));
+        }
+        if let Some(status) = self.status {
+            if !(400..=599).contains(&status) {
+                return Err(format!("error status {status} is not within 400..=599"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Rolls the dice for a request to `route` for `endpoint`.
+    pub fn fire(&self, route: Route, endpoint: &str) -> Option<ControlPlaneError> {
+        if self.route.is_some_and(|r| r != route) {
+            return None;
+        }
+        if let Some(pattern) = &self.endpoint {
+            if !glob_match(pattern.as_bytes(), endpoint.as_bytes()) {
+                return None;
+            }
+        }
+        if !thread_rng().gen_bool(self.percent / 100.0) {
+            return None;
+        }
+
+        let status = self
+            .status
+            .and_then(|s| StatusCode::from_u16(s).ok())
+            .unwrap_or_else(|| self.reason.default_status());
+        let message = self
+            .message
+            .clone()
+            .unwrap_or_else(|| format!("injected {:?} error", self.reason));
+        Some(
+            ControlPlaneError::new(status, self.reason, message)
+                .with_retry_delay(self.retry_delay_ms),
+        )
+    }
+}
@@ -0,0 +1,153 @@
+use std::{convert::Infallible, time::Duration};
+
+use axum::{
+    body::Body,
+    extract::{Query, Request, State},
+    http::header::CONTENT_LENGTH,
+    middleware::Next,
+    response::Response,
+};
+use futures_util::{stream, StreamExt};
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
+
+use crate::{
+    latency::Latency,
+    scenario::{glob_match, Route},
+    serve::CloseConnection,
+    Context,
+};
+
+/// Breaks the transport of some or all responses, below the control plane's error JSON.
+///
+/// ```toml
+/// [[faults]]
+/// route = "wake_compute"
+/// endpoint = "ep-slow-*"
+/// percent = 5
+/// kind = "stall"
+/// stall_ms = 30000
+/// ```
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FaultRule {
+    /// Both routes if unset.
+    pub route: Option<Route>,
+    /// Endpoint id or glob, all endpoints if unset.
+    pub endpoint: Option<String>,
+    #[serde(default = "FaultRule::always")]
+    pub percent: f64,
+    pub kind: FaultKind,
+    /// Added before the request is handled, for `latency` faults.
+    pub latency: Option<Latency>,
+    /// How long the body stalls halfway for `stall` faults, forever if unset.
+    pub stall_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultKind {
+    Latency,
+    /// Sends the headers and half the body, then waits.
+    Stall,
+    /// Closes the connection before a response is sent, with any other requests on it.
+    Close,
+    /// Answers with the body cut in half.
+    Malformed,
+}
+
+impl FaultRule {
+    fn always() -> f64 {
+        100.0
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=100.0).contains(&self.percent) {
+            return Err(format!(
+                "fault percent {} is not within 0..=100",
+                self.percent
+            ));
+        }
+        if (self.kind == FaultKind::Latency) != self.latency.is_some() {
+            return Err("latency must be set for latency faults, and only for them".to_owned());
+        }
+        if self.stall_ms.is_some() && self.kind != FaultKind::Stall {
+            return Err("stall_ms is only used by stall faults".to_owned());
+        }
+        Ok(())
+    }
+
+    fn fire(&self, route: Route, endpoint: &str) -> bool {
+        if self.route.is_some_and(|r| r != route) {
+            return false;
+        }
+        if let Some(pattern) = &self.endpoint {
+            if !glob_match(pattern.as_bytes(), endpoint.as_bytes()) {
+                return false;
+            }
+        }
+        thread_rng().gen_bool(self.percent / 100.0)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct FaultQuery {
+    endpointish: String,
+}
+
+/// Middleware applying the scenario's `[[faults]]` to a route.
+///
+/// Every latency fault that fires adds its latency, and the first other fault that fires
+/// breaks the response.
+pub async fn inject(
+    State((state, route)): State<(Context, Route)>,
+    Query(query): Query<FaultQuery>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let endpoint = &query.endpointish;
+    let mut fault = None;
+    for rule in &state.scenario.faults {
+        if !rule.fire(route, endpoint) {
+            continue;
+        }
+        match (&rule.latency, fault) {
+            (Some(latency), _) => tokio::time::sleep(latency.sample()).await,
+            (None, None) => fault = Some(rule),
+            (None, Some(_)) => {}
+        }
+    }
+
+    let Some(rule) = fault else {
+        return next.run(req).await;
+    };
+    println!("Injecting {:?} fault for {endpoint}", rule.kind);
+    if rule.kind == FaultKind::Close {
+        if let Some(close) = req.extensions().get::<CloseConnection>() {
+            close.close();
+        }
+        // the connection goes away with this request, before a response could be written
+        return std::future::pending().await;
+    }
+
+    let (mut parts, body) = next.run(req).await.into_parts();
+    let mut body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+    let tail = body.split_off(body.len() / 2);
+    parts.headers.remove(CONTENT_LENGTH);
+    let body = match rule.kind {
+        FaultKind::Stall => {
+            let stall_ms = rule.stall_ms;
+            let head = stream::once(async { Ok::<_, Infallible>(body) });
+            let tail = stream::once(async move {
+                match stall_ms {
+                    Some(ms) => tokio::time::sleep(Duration::from_millis(ms)).await,
+                    None => std::future::pending().await,
+                }
+                Ok(tail)
+            });
+            Body::from_stream(head.chain(tail))
+        }
+        _ => Body::from(body),
+    };
+    Response::from_parts(parts, body)
+}
@@ -0,0 +1,78 @@
+use serde::Deserialize;
+
+/// Groups numbered endpoints into branches, projects and accounts.
+///
+/// Endpoint `ep-{name}-{n}` belongs to branch `n / endpoints_per_branch`, and so on up.
+/// The first endpoint of each branch is its primary, the others are read replicas.
+///
+/// ```toml
+/// [hierarchy]
+/// endpoints_per_branch = 2
+/// branches_per_project = 3
+/// projects_per_account = 10
+/// ```
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hierarchy {
+    #[serde(default = "Hierarchy::one")]
+    pub endpoints_per_branch: u64,
+    #[serde(default = "Hierarchy::one")]
+    pub branches_per_project: u64,
+    #[serde(default = "Hierarchy::one")]
+    pub projects_per_account: u64,
+}
+
+/// Where an endpoint sits in the hierarchy.
+pub struct Ids {
+    pub project_id: String,
+    pub branch_id: String,
+    pub account_id: Option<String>,
+}
+
+impl Ids {
+    /// Without a hierarchy every endpoint `ep-x` is alone in project `pr-x`.
+    pub fn new(hierarchy: Option<&Hierarchy>, endpoint: &str) -> Self {
+        hierarchy
+            .and_then(|h| h.ids(endpoint))
+            .unwrap_or_else(|| Self {
+                project_id: endpoint
+                    .strip_prefix("ep-")
+                    .map(|s| format!("pr-{s}"))
+                    .unwrap_or_else(|| endpoint.to_owned()),
+                branch_id: "main".to_owned(),
+                account_id: None,
+            })
+    }
+}
+
+impl Hierarchy {
+    fn one() -> u64 {
+        1
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.endpoints_per_branch == 0
+            || self.branches_per_project == 0
+            || self.projects_per_account == 0
+        {
+            return Err("hierarchy sizes must be positive".to_owned());
+        }
+        Ok(())
+    }
+
+    /// Returns `None` for endpoints without a numeric suffix, which stay on their own.
+    fn ids(&self, endpoint: &str) -> Option<Ids> {
+        let name = endpoint.strip_prefix("ep-").unwrap_or(endpoint);
+        let (name, n) = name.rsplit_once('-')?;
+        let n: u64 = n.parse().ok()?;
+
+        let branch = n / self.endpoints_per_branch;
+        let project = branch / self.branches_per_project;
+        let account = project / self.projects_per_account;
+        Some(Ids {
+            project_id: format!("pr-{name}-{project}"),
+            branch_id: format!("br-{name}-{project}-{}", branch % self.branches_per_project),
+            account_id: Some(format!("acc-{name}-{account}")),
+        })
+    }
+}
@@ -0,0 +1,281 @@
+use std::{
+    collections::VecDeque,
+    sync::RwLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use p256::ecdsa::{signature::Signer, Signature};
+use rand::{thread_rng, Rng};
+use rsa::{pkcs1v15, signature::SignatureEncoding, traits::PublicKeyParts, RsaPrivateKey};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use sha2::Sha256;
+
+use crate::{scenario::glob_match, Context};
+
+/// Serves the proxy's JWT auth rules and signs tokens that pass them.
+///
+/// ```toml
+/// [jwt]
+/// algorithm = "ES256"
+/// endpoints = ["ep-jwt-*"]
+/// role_names = ["demo"]
+/// audience = "neon-bench"
+/// ```
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JwtConfig {
+    #[serde(default)]
+    pub algorithm: Algorithm,
+    /// Endpoint ids or globs that get an auth rule, all endpoints if unset.
+    pub endpoints: Option<Vec<String>>,
+    /// Roles allowed to log in with a token.
+    #[serde(default = "JwtConfig::default_role_names")]
+    pub role_names: Vec<String>,
+    pub audience: Option<String>,
+    #[serde(default = "JwtConfig::default_issuer")]
+    pub issuer: String,
+}
+
+impl JwtConfig {
+    fn default_role_names() -> Vec<String> {
+        vec!["demo".to_owned()]
+    }
+
+    fn default_issuer() -> String {
+        "cplane-mock".to_owned()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+pub enum Algorithm {
+    RS256,
+    #[default]
+    ES256,
+}
+
+enum KeyPair {
+    Rsa(Box<pkcs1v15::SigningKey<Sha256>>),
+    Ec(p256::ecdsa::SigningKey),
+}
+
+struct SigningKey {
+    kid: String,
+    key: KeyPair,
+}
+
+impl SigningKey {
+    fn generate(algorithm: Algorithm) -> Self {
+        let kid = format!("{:016x}", thread_rng().gen::<u64>());
+        let key = match algorithm {
+            Algorithm::RS256 => {
+                let key = RsaPrivateKey::new(&mut thread_rng(), 2048).unwrap();
+                KeyPair::Rsa(Box::new(pkcs1v15::SigningKey::new(key)))
+            }
+            Algorithm::ES256 => KeyPair::Ec(p256::ecdsa::SigningKey::random(&mut thread_rng())),
+        };
+        println!("Generated {algorithm:?} JWT signing key {kid}");
+        Self { kid, key }
+    }
+
+    fn jwk(&self) -> Value {
+        match &self.key {
+            KeyPair::Rsa(key) => {
+                let key: &RsaPrivateKey = (**key).as_ref();
+                json!({
+                    "kty": "RSA",
+                    "kid": self.kid,
+                    "alg": "RS256",
+                    "use": "sig",
+                    "n": base64url(&key.n().to_bytes_be()),
+                    "e": base64url(&key.e().to_bytes_be()),
+                })
+            }
+            KeyPair::Ec(key) => {
+                let point = key.verifying_key().to_encoded_point(false);
+                json!({
+                    "kty": "EC",
+                    "kid": self.kid,
+                    "alg": "ES256",
+                    "use": "sig",
+                    "crv": "P-256",
+                    "x": base64url(point.x().unwrap()),
+                    "y": base64url(point.y().unwrap()),
+                })
+            }
+        }
+    }
+
+    fn sign(&self, claims: &Value) -> String {
+        let alg = match self.key {
+            KeyPair::Rsa(_) => "RS256",
+            KeyPair::Ec(_) => "ES256",
+        };
+        let header = json!({ "alg": alg, "typ": "JWT", "kid": self.kid });
+        let signing_input = format!(
+            "{}.{}",
+            base64url(header.to_string().as_bytes()),
+            base64url(claims.to_string().as_bytes())
+        );
+        let signature = match &self.key {
+            KeyPair::Rsa(key) => key.sign(signing_input.as_bytes()).to_vec(),
+            KeyPair::Ec(key) => {
+                let signature: Signature = key.sign(signing_input.as_bytes());
+                signature.to_bytes().to_vec()
+            }
+        };
+        format!("{signing_input}.{}", base64url(&signature))
+    }
+}
+
+/// How many keys the JWKS keeps publishing, so tokens signed before a rotation stay valid.
+const PUBLISHED_KEYS: usize = 2;
+
+pub struct Jwks {
+    config: JwtConfig,
+    /// Newest first. Tokens are signed with the front key.
+    keys: RwLock<VecDeque<SigningKey>>,
+    /// Where the proxy fetches the JWKS from, `$JWKS_URL`.
+    url: String,
+}
+
+impl Jwks {
+    pub fn new(config: JwtConfig) -> Self {
+        let key = SigningKey::generate(config.algorithm);
+        Self {
+            keys: RwLock::new(VecDeque::from([key])),
+            config,
+            url: std::env::var("JWKS_URL")
+                .unwrap_or_else(|_| "http://localhost:3010/jwks.json".to_owned()),
+        }
+    }
+
+    /// Signs new tokens with a fresh key. The previous key stays in the JWKS.
+    pub fn rotate(&self) -> String {
+        let key = SigningKey::generate(self.config.algorithm);
+        let kid = key.kid.clone();
+        let mut keys = self.keys.write().unwrap();
+        keys.push_front(key);
+        keys.truncate(PUBLISHED_KEYS);
+        kid
+    }
+}
+
+fn base64url(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+#[derive(Serialize)]
+pub struct EndpointJwksResponse {
+    jwks: Vec<JwksSettings>,
+}
+
+#[derive(Serialize)]
+struct JwksSettings {
+    id: String,
+    jwks_url: String,
+    provider_name: String,
+    jwt_audience: Option<String>,
+    role_names: Vec<String>,
+}
+
+/// The auth rules the proxy fetches for an endpoint before accepting a JWT.
+pub async fn endpoint_jwks(
+    state: State<Context>,
+    Path(endpoint): Path<String>,
+) -> Json<EndpointJwksResponse> {
+    println!("endpoint_jwks: {endpoint}");
+    let Some(jwks) = &state.jwks else {
+        return Json(EndpointJwksResponse { jwks: vec![] });
+    };
+
+    let config = &jwks.config;
+    let enabled = config.endpoints.as_ref().is_none_or(|patterns| {
+        patterns
+            .iter()
+            .any(|p| glob_match(p.as_bytes(), endpoint.as_bytes()))
+    });
+    if !enabled {
+        return Json(EndpointJwksResponse { jwks: vec![] });
+    }
+
+    Json(EndpointJwksResponse {
+        jwks: vec![JwksSettings {
+            id: "cplane-mock".to_owned(),
+            jwks_url: jwks.url.clone(),
+            provider_name: "cplane-mock".to_owned(),
+            jwt_audience: config.audience.clone(),
+            role_names: config.role_names.clone(),
+        }],
+    })
+}
+
+/// The JSON Web Key Set with the current and previous signing keys.
+pub async fn jwks(state: State<Context>) -> Result<Json<Value>, StatusCode> {
+    let jwks = state.jwks.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    let keys = jwks.keys.read().unwrap();
+    Ok(Json(json!({
+        "keys": keys.iter().map(SigningKey::jwk).collect::<Vec<_>>(),
+    })))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenRequest {
+    #[serde(default = "TokenRequest::default_sub")]
+    sub: String,
+    role: Option<String>,
+    #[serde(default = "TokenRequest::default_expires_in_secs")]
+    expires_in_secs: i64,
+    /// Extra claims, these override the generated ones.
+    #[serde(default)]
+    claims: Map<String, Value>,
+}
+
+impl TokenRequest {
+    fn default_sub() -> String {
+        "bench".to_owned()
+    }
+
+    fn default_expires_in_secs() -> i64 {
+        3600
+    }
+}
+
+/// Signs a token for the bench to connect with. A negative `expires_in_secs` gives an expired one.
+pub async fn mint_token(
+    state: State<Context>,
+    Json(req): Json<TokenRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let jwks = state.jwks.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    let config = &jwks.config;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs() as i64;
+    let exp = now
+        .checked_add(req.expires_in_secs)
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    let mut claims = Map::new();
+    claims.insert("iss".to_owned(), json!(config.issuer));
+    claims.insert("sub".to_owned(), json!(req.sub));
+    claims.insert("iat".to_owned(), json!(now));
+    claims.insert("nbf".to_owned(), json!(now));
+    claims.insert("exp".to_owned(), json!(exp));
+    if let Some(audience) = &config.audience {
+        claims.insert("aud".to_owned(), json!(audience));
+    }
+    if let Some(role) = req.role {
+        claims.insert("role".to_owned(), json!(role));
+    }
+    claims.extend(req.claims);
+
+    let token = jwks.keys.read().unwrap()[0].sign(&Value::Object(claims));
+    Ok(Json(json!({ "token": token })))
+}
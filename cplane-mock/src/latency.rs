@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+use rand::{thread_rng, Rng};
+use rand_distr::{LogNormal, Normal};
+use serde::Deserialize;
+
+/// A latency distribution, in milliseconds.
+///
+/// ```toml
+/// latency = { distribution = "fixed", ms = 5 }
+/// latency = { distribution = "normal", mean_ms = 500, std_dev_ms = 100 }
+/// latency = { distribution = "lognormal", mean_ms = 3000, std_dev_ms = 1500 }
+/// ```
+#[derive(Clone, Copy, Deserialize)]
+#[serde(try_from = "LatencyConfig")]
+pub enum Latency {
+    Fixed(Duration),
+    Normal(Normal<f64>),
+    LogNormal(LogNormal<f64>),
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "distribution", rename_all = "lowercase", deny_unknown_fields)]
+enum LatencyConfig {
+    Fixed { ms: f64 },
+    Normal { mean_ms: f64, std_dev_ms: f64 },
+    LogNormal { mean_ms: f64, std_dev_ms: f64 },
+}
+
+impl TryFrom<LatencyConfig> for Latency {
+    type Error = String;
+
+    fn try_from(config: LatencyConfig) -> Result<Self, Self::Error> {
+        match config {
+            LatencyConfig::Fixed { ms } => Duration::try_from_secs_f64(ms / 1000.0)
+                .map(Latency::Fixed)
+                .map_err(|e| format!("invalid fixed latency {ms}ms: {e}")),
+            LatencyConfig::Normal {
+                mean_ms,
+                std_dev_ms,
+            } => Normal::new(mean_ms, std_dev_ms)
+                .map(Latency::Normal)
+                .map_err(|e| format!("invalid normal latency: {e}")),
+            LatencyConfig::LogNormal {
+                mean_ms,
+                std_dev_ms,
+            } => LogNormal::from_mean_cv(mean_ms, std_dev_ms / mean_ms)
+                .map(Latency::LogNormal)
+                .map_err(|e| format!("invalid lognormal latency: {e}")),
+        }
+    }
+}
+
+impl Default for Latency {
+    fn default() -> Self {
+        Latency::Fixed(Duration::ZERO)
+    }
+}
+
+impl Latency {
+    pub fn sample(&self) -> Duration {
+        let ms = match self {
+            Latency::Fixed(d) => return *d,
+            Latency::Normal(dist) => thread_rng().sample(dist),
+            Latency::LogNormal(dist) => thread_rng().sample(dist),
+        };
+        // normal samples can go negative, treat those as no delay
+        Duration::try_from_secs_f64(ms / 1000.0).unwrap_or_default()
+    }
+}
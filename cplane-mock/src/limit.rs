@@ -0,0 +1,366 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Query, Request, State},
+    http::{header::RETRY_AFTER, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{
+    error::{ControlPlaneError, Reason},
+    Context,
+};
+
+/// Backpressure on the proxy routes, like the real control plane's rate limits.
+///
+/// Requests over a limit wait up to `max_queue_wait_ms` for capacity, then get a 429 with
+/// `Retry-After`. Without `[limits]` in the scenario concurrency is unbounded.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    /// Requests handled at once across all endpoints.
+    pub max_concurrent: Option<usize>,
+    pub max_concurrent_per_endpoint: Option<usize>,
+    /// Token bucket shared by all endpoints.
+    pub rate: Option<Rate>,
+    /// Token bucket of each endpoint.
+    pub endpoint_rate: Option<Rate>,
+    #[serde(default)]
+    pub max_queue_wait_ms: u64,
+    /// Retry delay suggested when the concurrency cap is hit. Rate limits suggest the time
+    /// until the next token.
+    #[serde(default = "Limits::default_retry_after_ms")]
+    pub retry_after_ms: u64,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rate {
+    pub requests_per_sec: f64,
+    /// Bucket size, `requests_per_sec` if unset.
+    pub burst: Option<f64>,
+}
+
+impl Limits {
+    fn default_retry_after_ms() -> u64 {
+        1000
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_concurrent == Some(0) || self.max_concurrent_per_endpoint == Some(0) {
+            return Err("concurrency limits must be positive".to_owned());
+        }
+        for rate in [&self.rate, &self.endpoint_rate].into_iter().flatten() {
+            if !(rate.requests_per_sec.is_finite() && rate.requests_per_sec > 0.0) {
+                return Err("requests_per_sec must be positive".to_owned());
+            }
+            if rate.burst.is_some_and(|b| !(b.is_finite() && b >= 1.0)) {
+                return Err("burst must be at least 1".to_owned());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Rate {
+    fn burst(&self) -> f64 {
+        self.burst.unwrap_or(self.requests_per_sec.max(1.0))
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(rate: &Rate) -> Self {
+        Self {
+            tokens: rate.burst(),
+            updated: Instant::now(),
+        }
+    }
+
+    /// Refills the bucket and returns how long until a token is available.
+    fn wait(&mut self, rate: &Rate, now: Instant) -> Duration {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.requests_per_sec).min(rate.burst());
+        self.updated = now;
+        Duration::from_secs_f64((1.0 - self.tokens).max(0.0) / rate.requests_per_sec)
+    }
+}
+
+pub struct Limiter {
+    limits: Limits,
+    global: Option<Arc<Semaphore>>,
+    endpoint_permits: Mutex<HashMap<String, Arc<Semaphore>>>,
+    global_bucket: Mutex<Option<Bucket>>,
+    endpoint_buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl Limiter {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            global: limits.max_concurrent.map(|n| Arc::new(Semaphore::new(n))),
+            endpoint_permits: Mutex::default(),
+            global_bucket: Mutex::new(limits.rate.as_ref().map(Bucket::full)),
+            endpoint_buckets: Mutex::default(),
+            limits,
+        }
+    }
+
+    /// Takes a token from the global and the endpoint's bucket, waiting for both to refill
+    /// if that's within the queue wait. Returns the wait it would take otherwise.
+    fn take_tokens(
+        &self,
+        endpoint: &str,
+        now: Instant,
+        max_wait: Duration,
+    ) -> Result<Duration, Duration> {
+        self.with_buckets(endpoint, |mut buckets| {
+            let wait = buckets
+                .iter_mut()
+                .map(|(rate, bucket)| bucket.wait(rate, now))
+                .max()
+                .unwrap_or(Duration::ZERO);
+            if wait > max_wait {
+                return Err(wait);
+            }
+            // tokens may go negative, reserving them for requests that wait
+            for (_, bucket) in buckets {
+                bucket.tokens -= 1.0;
+            }
+            Ok(wait)
+        })
+    }
+
+    /// Returns the tokens of a request that was rejected after all, so it doesn't count
+    /// against the rate.
+    fn refund_tokens(&self, endpoint: &str) {
+        self.with_buckets(endpoint, |buckets| {
+            for (rate, bucket) in buckets {
+                bucket.tokens = (bucket.tokens + 1.0).min(rate.burst());
+            }
+        })
+    }
+
+    /// Runs `f` on the configured buckets, global first.
+    fn with_buckets<T>(&self, endpoint: &str, f: impl FnOnce(Vec<(&Rate, &mut Bucket)>) -> T) -> T {
+        let mut global = self.global_bucket.lock().unwrap();
+        let mut endpoints = self.endpoint_buckets.lock().unwrap();
+
+        let global = self.limits.rate.as_ref().zip(global.as_mut());
+        let endpoint = self.limits.endpoint_rate.as_ref().map(|rate| {
+            let bucket = endpoints
+                .entry(endpoint.to_owned())
+                .or_insert_with(|| Bucket::full(rate));
+            (rate, bucket)
+        });
+        f(global.into_iter().chain(endpoint).collect())
+    }
+
+    async fn acquire(
+        semaphore: Option<Arc<Semaphore>>,
+        deadline: tokio::time::Instant,
+    ) -> Result<Option<OwnedSemaphorePermit>, ()> {
+        let Some(semaphore) = semaphore else {
+            return Ok(None);
+        };
+        match tokio::time::timeout_at(deadline, semaphore.acquire_owned()).await {
+            Ok(permit) => Ok(Some(permit.unwrap())),
+            Err(_) => Err(()),
+        }
+    }
+
+    fn endpoint_semaphore(&self, endpoint: &str) -> Option<Arc<Semaphore>> {
+        let n = self.limits.max_concurrent_per_endpoint?;
+        let mut permits = self.endpoint_permits.lock().unwrap();
+        Some(
+            permits
+                .entry(endpoint.to_owned())
+                .or_insert_with(|| Arc::new(Semaphore::new(n)))
+                .clone(),
+        )
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LimitQuery {
+    endpointish: String,
+}
+
+/// Middleware applying `[limits]` to the routes it wraps.
+pub async fn enforce(
+    state: State<Context>,
+    Query(query): Query<LimitQuery>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(limiter) = &state.limiter else {
+        return next.run(req).await;
+    };
+    let endpoint = &query.endpointish;
+    let max_wait = Duration::from_millis(limiter.limits.max_queue_wait_ms);
+    let deadline = tokio::time::Instant::now() + max_wait;
+
+    match limiter.take_tokens(endpoint, Instant::now(), max_wait) {
+        Ok(wait) => tokio::time::sleep(wait).await,
+        Err(retry_after) => {
+            println!("Rate limited {endpoint}, retry after {retry_after:?}");
+            return too_many_requests(Reason::RateLimitExceeded, retry_after);
+        }
+    }
+
+    let retry_after = Duration::from_millis(limiter.limits.retry_after_ms);
+    let Ok(_global) = Limiter::acquire(limiter.global.clone(), deadline).await else {
+        println!("Concurrency limit reached, rejecting {endpoint}");
+        limiter.refund_tokens(endpoint);
+        return too_many_requests(Reason::ConcurrencyLimitReached, retry_after);
+    };
+    let Ok(_endpoint) = Limiter::acquire(limiter.endpoint_semaphore(endpoint), deadline).await
+    else {
+        println!("Concurrency limit of {endpoint} reached");
+        limiter.refund_tokens(endpoint);
+        return too_many_requests(Reason::ConcurrencyLimitReached, retry_after);
+    };
+
+    next.run(req).await
+}
+
+fn too_many_requests(reason: Reason, retry_after: Duration) -> Response {
+    let mut response = ControlPlaneError::too_many_requests(reason, retry_after).into_response();
+    // Retry-After only takes whole seconds, retry_info has the exact delay
+    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(secs));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate(requests_per_sec: f64, burst: Option<f64>) -> Rate {
+        Rate {
+            requests_per_sec,
+            burst,
+        }
+    }
+
+    fn limiter(rate: Option<Rate>, endpoint_rate: Option<Rate>) -> Limiter {
+        Limiter::new(Limits {
+            max_concurrent: None,
+            max_concurrent_per_endpoint: None,
+            rate,
+            endpoint_rate,
+            max_queue_wait_ms: 0,
+            retry_after_ms: 1000,
+        })
+    }
+
+    #[test]
+    fn bucket_refills_up_to_burst() {
+        let rate = rate(10.0, Some(3.0));
+        let mut bucket = Bucket::full(&rate);
+        let start = bucket.updated;
+        assert_eq!(bucket.wait(&rate, start), Duration::ZERO);
+
+        bucket.tokens = 0.0;
+        assert_eq!(bucket.wait(&rate, start), Duration::from_millis(100));
+        assert_eq!(
+            bucket.wait(&rate, start + Duration::from_millis(50)),
+            Duration::from_millis(50)
+        );
+        assert_eq!(
+            bucket.wait(&rate, start + Duration::from_millis(100)),
+            Duration::ZERO
+        );
+
+        // an idle bucket fills up to the burst, not beyond
+        bucket.wait(&rate, start + Duration::from_secs(60));
+        assert_eq!(bucket.tokens, 3.0);
+    }
+
+    #[test]
+    fn burst_defaults_to_rate() {
+        assert_eq!(rate(5.0, None).burst(), 5.0);
+        assert_eq!(rate(0.5, None).burst(), 1.0);
+        assert_eq!(rate(5.0, Some(2.0)).burst(), 2.0);
+    }
+
+    #[test]
+    fn burst_then_reservations() {
+        let limiter = limiter(Some(rate(10.0, Some(2.0))), None);
+        let now = Instant::now();
+        let max_wait = Duration::from_secs(1);
+        assert_eq!(limiter.take_tokens("ep", now, max_wait), Ok(Duration::ZERO));
+        assert_eq!(limiter.take_tokens("ep", now, max_wait), Ok(Duration::ZERO));
+        // requests past the burst queue up behind each other
+        assert_eq!(
+            limiter.take_tokens("ep", now, max_wait),
+            Ok(Duration::from_millis(100))
+        );
+        assert_eq!(
+            limiter.take_tokens("ep", now, max_wait),
+            Ok(Duration::from_millis(200))
+        );
+    }
+
+    #[test]
+    fn rejects_past_max_wait() {
+        let limiter = limiter(Some(rate(10.0, Some(1.0))), Some(rate(1.0, Some(1.0))));
+        let now = Instant::now();
+        let max_wait = Duration::from_millis(500);
+        assert_eq!(limiter.take_tokens("a", now, max_wait), Ok(Duration::ZERO));
+
+        // the endpoint's bucket is slower than the global one and decides the wait
+        assert_eq!(
+            limiter.take_tokens("a", now, max_wait),
+            Err(Duration::from_secs(1))
+        );
+        // a rejected request takes no tokens
+        assert_eq!(
+            limiter.take_tokens("a", now, max_wait),
+            Err(Duration::from_secs(1))
+        );
+        assert_eq!(
+            limiter.take_tokens("b", now, max_wait),
+            Ok(Duration::from_millis(100))
+        );
+        assert_eq!(
+            limiter.take_tokens("a", now + Duration::from_secs(1), max_wait),
+            Ok(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn refunds_tokens() {
+        let limiter = limiter(Some(rate(10.0, Some(1.0))), Some(rate(1.0, Some(1.0))));
+        let now = Instant::now();
+        let max_wait = Duration::from_secs(10);
+        assert_eq!(limiter.take_tokens("a", now, max_wait), Ok(Duration::ZERO));
+        limiter.refund_tokens("a");
+        assert_eq!(limiter.take_tokens("a", now, max_wait), Ok(Duration::ZERO));
+        assert_eq!(
+            limiter.take_tokens("a", now, max_wait),
+            Ok(Duration::from_secs(1))
+        );
+        // a refund never overfills the bucket
+        limiter.refund_tokens("a");
+        limiter.refund_tokens("a");
+        limiter.refund_tokens("a");
+        assert_eq!(limiter.take_tokens("a", now, max_wait), Ok(Duration::ZERO));
+        assert_eq!(
+            limiter.take_tokens("a", now, max_wait),
+            Ok(Duration::from_secs(1))
+        );
+    }
+}
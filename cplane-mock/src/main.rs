@@ -1,41 +1,163 @@
+mod admin;
+mod cold_start;
+mod compute;
+mod console_redirect;
+mod credentials;
+mod error;
+mod fault;
+mod hierarchy;
+mod jwt;
+mod latency;
+mod limit;
+mod metrics;
+mod notify;
+mod record;
+mod role_sync;
+mod scenario;
+mod serve;
+mod tls;
+
+use std::sync::Arc;
+
+use admin::Overrides;
 use axum::{
     extract::{Query, State},
-    routing::get,
-    Json, Router,
+    middleware,
+    response::Response,
+    routing::{get, post},
+    Extension, Router,
 };
+use compute::{ComputePool, Computes};
+use credentials::CredentialStore;
+use error::ControlPlaneError;
+use jwt::Jwks;
+use limit::Limiter;
+use metrics::Metrics;
+use notify::Notifier;
+use record::{Call, Received, Recorder, Replayer};
+use role_sync::RoleSync;
+use scenario::{Route, Scenario};
 use serde::{Deserialize, Serialize};
 use tokio::signal::unix::{signal, SignalKind};
 
 #[derive(Clone)]
 struct Context {
-    compute_address: String,
+    compute_pool: Arc<ComputePool>,
+    scenario: Arc<Scenario>,
+    credentials: Arc<CredentialStore>,
+    computes: Option<Arc<Computes>>,
+    metrics: Arc<Metrics>,
+    overrides: Arc<Overrides>,
+    notifier: Option<Arc<Notifier>>,
+    jwks: Option<Arc<Jwks>>,
+    recorder: Option<Arc<Recorder>>,
+    replayer: Option<Arc<Replayer>>,
+    limiter: Option<Arc<Limiter>>,
+    role_sync: Option<Arc<RoleSync>>,
 }
 
 #[tokio::main]
 async fn main() {
     println!("Starting cplane-mock");
 
+    let compute_pool = ComputePool::new(
+        &std::env::var("PROXY_COMPUTE_ADDR").unwrap(),
+        std::env::var("COMPUTE_DOMAIN")
+            .ok()
+            .filter(|domain| !domain.is_empty()),
+    );
+    let role_sync = RoleSync::from_env(compute_pool.addresses());
+    let mut scenario = Scenario::from_env(role_sync.is_some());
+    let jwks = scenario.jwt.take().map(Jwks::new);
+    let limiter = scenario.limits.take().map(Limiter::new);
+    let metrics = Metrics::new(scenario.cache_ttl);
+    let credentials = CredentialStore::new(&scenario);
+    let computes = scenario.lifecycle.as_ref().map(Computes::new);
+    let context = Context {
+        compute_pool: Arc::new(compute_pool),
+        scenario: Arc::new(scenario),
+        credentials: Arc::new(credentials),
+        computes: computes.map(Arc::new),
+        metrics: Arc::new(metrics),
+        overrides: Arc::new(Overrides::default()),
+        notifier: std::env::var("REDIS_NOTIFICATIONS_ADDR")
+            .ok()
+            .map(|addr| Arc::new(Notifier::new(addr))),
+        jwks: jwks.map(Arc::new),
+        recorder: std::env::var("CPLANE_RECORD")
+            .ok()
+            .map(|path| Arc::new(Recorder::new(&path))),
+        replayer: std::env::var("CPLANE_REPLAY")
+            .ok()
+            .map(|path| Arc::new(Replayer::new(&path))),
+        limiter: limiter.map(Arc::new),
+        role_sync: role_sync.map(Arc::new),
+    };
+
+    if let Some(role_sync) = context.role_sync.clone() {
+        let credentials = context.credentials.clone();
+        tokio::spawn(async move { role_sync.push_until_done(|| credentials.roles()).await });
+    }
+
     let app = Router::new()
         .route(
             "/proxy/api/v1/get_endpoint_access_control",
-            get(get_endpoint_access_control),
+            get(get_endpoint_access_control).route_layer(middleware::from_fn_with_state(
+                (context.clone(), Route::GetEndpointAccessControl),
+                fault::inject,
+            )),
         )
         .route(
             "/proxy/api/v1/wake_compute",
-            get(wake_compute),
+            get(wake_compute).route_layer(middleware::from_fn_with_state(
+                (context.clone(), Route::WakeCompute),
+                fault::inject,
+            )),
         )
-        .with_state(Context {
-            compute_address: std::env::var("PROXY_COMPUTE_ADDR").unwrap(),
-        });
+        .route_layer(middleware::from_fn_with_state(
+            context.clone(),
+            limit::enforce,
+        ))
+        .route_layer(middleware::from_fn(record::received))
+        .route(
+            "/proxy/api/v1/endpoints/:endpoint/jwks",
+            get(jwt::endpoint_jwks),
+        )
+        .route("/jwks.json", get(jwt::jwks))
+        .route("/jwt/token", post(jwt::mint_token))
+        .route(
+            "/console-redirect/:session_id",
+            get(console_redirect::open_link),
+        )
+        .route("/metrics", get(metrics::metrics))
+        .route("/stats", get(metrics::stats))
+        .merge(admin::router())
+        .route_layer(middleware::from_fn_with_state(
+            context.clone(),
+            metrics::track,
+        ))
+        .with_state(context);
 
     let mut signal = signal(SignalKind::terminate()).unwrap();
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3010").await.unwrap();
-    axum::serve(listener, app)
-        .with_graceful_shutdown(async move {
-            signal.recv().await;
-        })
-        .await
-        .unwrap();
+    let shutdown = async move {
+        signal.recv().await;
+    };
+    let addr = std::env::var("CPLANE_LISTEN_ADDR").unwrap_or_else(|_| "0.0.0.0:3010".to_owned());
+    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
+    let tls = match (
+        std::env::var("CPLANE_TLS_CERT"),
+        std::env::var("CPLANE_TLS_KEY"),
+    ) {
+        (Ok(cert), Ok(key)) => {
+            println!("Listening on https://{addr}");
+            Some(tls::config(&cert, &key))
+        }
+        _ => {
+            println!("Listening on http://{addr}");
+            None
+        }
+    };
+    serve::serve(listener, tls, app, shutdown).await;
 }
 
 #[derive(Deserialize)]
@@ -44,18 +166,15 @@ struct RoleSecretQuery {
     endpointish: String,
 }
 
-/// scram_sha_256("password")
-const SCRAM_PASSWORD: &str = "SCRAM-SHA-256$4096:M2ZX/kfDSd3vv5iFO/QNUA==$mookt3EiEpd/vMqGbd7df3qVwfyUfM91Ps72sNewNg4=:3nMi8eBSHggIBNSgAik6lQnE3hQcsS+myylZlYgNA1U=";
-
 #[derive(Serialize)]
 struct RoleAccessControl {
-    scram_secret: Option<&'static str>,
+    scram_secret: Option<String>,
 }
 
 #[derive(Serialize)]
 struct RoleSecretResponse {
     role_access: RoleAccessControl,
-    role_secret: &'static str,
+    role_secret: String,
     allowed_ips: Option<Vec<String>>,
     allowed_vpc_endpoint_ids: Option<Vec<String>>,
     project_id: Option<String>,
@@ -64,20 +183,86 @@ struct RoleSecretResponse {
     block_vpc_connections: Option<bool>,
 }
 
-async fn get_endpoint_access_control(query: Query<RoleSecretQuery>) -> Json<RoleSecretResponse> {
-    let project_id = endpoint_id_to_project_id(&query.endpointish);
-    println!("get_endpoint_access_control: project_id: {}", project_id);
-    Json(RoleSecretResponse {
+async fn get_endpoint_access_control(
+    query: Query<RoleSecretQuery>,
+    state: State<Context>,
+    Extension(Received(started)): Extension<Received>,
+) -> Response {
+    println!(
+        "get_endpoint_access_control: project_id: {}, role: {}",
+        state.scenario.ids(&query.endpointish).project_id,
+        query.role
+    );
+    state
+        .metrics
+        .record_access_control(&query.endpointish, &query.role);
+    let call = Call {
+        route: Route::GetEndpointAccessControl,
+        endpoint: &query.endpointish,
+        role: Some(&query.role),
+        application_name: None,
+        session_id: None,
+    };
+    if let Some(replayer) = &state.replayer {
+        if let Some(response) = replayer.replay(&call, started).await {
+            return response;
+        }
+    }
+
+    let result = endpoint_access_control(&query, &state);
+    record::respond(state.recorder.as_deref(), call, started, result)
+}
+
+fn endpoint_access_control(
+    query: &RoleSecretQuery,
+    state: &Context,
+) -> Result<RoleSecretResponse, ControlPlaneError> {
+    let ids = state.scenario.ids(&query.endpointish);
+    if let Some(err) = state
+        .scenario
+        .injected_error(Route::GetEndpointAccessControl, &query.endpointish)
+    {
+        return Err(err);
+    }
+
+    let overrides = state.overrides.get(&query.endpointish);
+    if overrides.deleted {
+        return Err(ControlPlaneError::endpoint_not_found());
+    }
+
+    let secret = state
+        .credentials
+        .secret(&state.scenario, &query.endpointish, &query.role)
+        .ok_or_else(ControlPlaneError::role_not_found)?;
+    let rule = state.scenario.endpoint(&query.endpointish);
+    let access = overrides.access;
+    Ok(RoleSecretResponse {
         role_access: RoleAccessControl {
-            scram_secret: Some(SCRAM_PASSWORD),
+            scram_secret: secret.scram_secret(),
         },
-        role_secret: SCRAM_PASSWORD,
-        allowed_ips: Some(vec![]),
-        allowed_vpc_endpoint_ids: Some(vec![]),
-        project_id: Some(project_id),
-        account_id: None,
-        block_public_connections: Some(false),
-        block_vpc_connections: Some(false),
+        role_secret: secret.role_secret(),
+        allowed_ips: Some(
+            access
+                .allowed_ips
+                .unwrap_or_else(|| rule.map_or_else(Vec::new, |r| r.allowed_ips.clone())),
+        ),
+        allowed_vpc_endpoint_ids: Some(
+            access.allowed_vpc_endpoint_ids.unwrap_or_else(|| {
+                rule.map_or_else(Vec::new, |r| r.allowed_vpc_endpoint_ids.clone())
+            }),
+        ),
+        project_id: Some(ids.project_id),
+        account_id: ids.account_id,
+        block_public_connections: Some(
+            access
+                .block_public_connections
+                .unwrap_or_else(|| rule.is_some_and(|r| r.block_public_connections)),
+        ),
+        block_vpc_connections: Some(
+            access
+                .block_vpc_connections
+                .unwrap_or_else(|| rule.is_some_and(|r| r.block_vpc_connections)),
+        ),
     })
 }
 
@@ -128,28 +313,73 @@ pub enum ColdStartInfo {
     WarmCached,
 }
 
-
 async fn wake_compute(
     query: Query<WakeComputeQuery>,
     state: State<Context>,
-) -> Json<WakeComputeResponse> {
-    println!("Received wake_compute request with params: {:?}", query.0.endpointish);
-    let project_id = endpoint_id_to_project_id(&query.endpointish);
-    Json(WakeComputeResponse {
-        address: state.compute_address.clone(),
+    Extension(Received(started)): Extension<Received>,
+) -> Response {
+    println!(
+        "Received wake_compute request with params: {:?}, application_name: {:?}, session_id: {:?}",
+        query.endpointish, query.application_name, query.session_id
+    );
+    let _in_flight = state.metrics.start_wake(&query.endpointish);
+    let call = Call {
+        route: Route::WakeCompute,
+        endpoint: &query.endpointish,
+        role: None,
+        application_name: query.application_name.as_deref(),
+        session_id: query.session_id.as_deref(),
+    };
+    if let Some(replayer) = &state.replayer {
+        if let Some(response) = replayer.replay(&call, started).await {
+            return response;
+        }
+    }
+
+    let result = wake(&query, &state).await;
+    record::respond(state.recorder.as_deref(), call, started, result)
+}
+
+async fn wake(
+    query: &WakeComputeQuery,
+    state: &Context,
+) -> Result<WakeComputeResponse, ControlPlaneError> {
+    if let Some(err) = state
+        .scenario
+        .injected_error(Route::WakeCompute, &query.endpointish)
+    {
+        return Err(err);
+    }
+
+    if state.overrides.get(&query.endpointish).deleted {
+        return Err(ControlPlaneError::endpoint_not_found());
+    }
+
+    let ids = state.scenario.ids(&query.endpointish);
+
+    let cold_start = state.scenario.cold_start(&query.endpointish);
+    let cold_start_info = match &state.computes {
+        Some(computes) => computes.wake(&query.endpointish, cold_start).await,
+        None => {
+            let (cold_start_info, latency) = cold_start.sample();
+            tokio::time::sleep(latency).await;
+            cold_start_info
+        }
+    };
+
+    let compute = state
+        .compute_pool
+        .assign(&query.endpointish, &state.scenario.migrations);
+
+    Ok(WakeComputeResponse {
+        address: compute.address,
         server_name: None,
         aux: MetricsAuxInfo {
-            endpoint_id: query.0.endpointish.clone(),
-            project_id,
-            branch_id: "main".to_string(),
-            compute_id: format!("compute-123"),
-            cold_start_info: ColdStartInfo::Warm,
+            endpoint_id: query.endpointish.clone(),
+            project_id: ids.project_id,
+            branch_id: ids.branch_id,
+            compute_id: compute.compute_id,
+            cold_start_info,
         },
     })
 }
-
-fn endpoint_id_to_project_id(s: &str) -> String {
-    s.strip_prefix("ep-")
-        .map(|s| format!("pr-{s}"))
-        .unwrap_or_else(|| s.to_owned())
-}
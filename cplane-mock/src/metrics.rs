@@ -0,0 +1,316 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::Duration,
+};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use prometheus::{
+    exponential_buckets, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::{scenario::Route, Context};
+
+/// Prometheus metrics of the traffic the proxy sends to cplane-mock, served at `/metrics`.
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    distinct_endpoints: IntGaugeVec,
+    distinct_roles: IntGauge,
+    wakes_in_flight: IntGauge,
+    wakes_in_flight_max: IntGauge,
+    cache_ttl: CacheTtl,
+    started: Instant,
+    seen: Mutex<Seen>,
+}
+
+/// TTLs of the proxy's caches, to tell which repeated requests it should have served itself.
+///
+/// The defaults match the proxy flags in docker-compose.yml.
+#[derive(Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheTtl {
+    #[serde(default = "CacheTtl::default_project_info_secs")]
+    pub project_info_secs: u64,
+    #[serde(default = "CacheTtl::default_wake_compute_secs")]
+    pub wake_compute_secs: u64,
+}
+
+impl CacheTtl {
+    fn default_project_info_secs() -> u64 {
+        3600
+    }
+
+    fn default_wake_compute_secs() -> u64 {
+        240
+    }
+}
+
+impl Default for CacheTtl {
+    fn default() -> Self {
+        Self {
+            project_info_secs: Self::default_project_info_secs(),
+            wake_compute_secs: Self::default_wake_compute_secs(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Seen {
+    access_control_endpoints: HashSet<String>,
+    /// Last fetch of each (endpoint, role).
+    roles: HashMap<(String, String), Instant>,
+    wakes: HashMap<String, WakeSeen>,
+    stats: Stats,
+}
+
+#[derive(Default)]
+struct WakeSeen {
+    in_flight: u32,
+    last_done: Option<Instant>,
+}
+
+/// Counters served at `/stats`.
+#[derive(Clone, Default, Serialize)]
+struct Stats {
+    access_control_requests: u64,
+    /// Fetched again before the proxy's project info cache entry would have expired.
+    access_control_refetched_within_ttl: u64,
+    wake_compute_requests: u64,
+    /// Woken while an earlier wake of the same endpoint was still in flight.
+    wake_compute_concurrent_duplicates: u64,
+    /// Woken again before the proxy's wake compute cache entry would have expired.
+    wake_compute_refetched_within_ttl: u64,
+}
+
+impl Metrics {
+    pub fn new(cache_ttl: CacheTtl) -> Self {
+        let registry = Registry::new();
+
+        let requests = IntCounterVec::new(
+            Opts::new(
+                "cplane_requests_total",
+                "Requests served, by route and status",
+            ),
+            &["route", "status"],
+        )
+        .unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "cplane_request_duration_seconds",
+                "Time to answer a request, including simulated latency",
+            )
+            .buckets(exponential_buckets(0.001, 2.0, 16).unwrap()),
+            &["route", "status"],
+        )
+        .unwrap();
+        let distinct_endpoints = IntGaugeVec::new(
+            Opts::new(
+                "cplane_distinct_endpoints",
+                "Distinct endpoints requested so far, by route",
+            ),
+            &["route"],
+        )
+        .unwrap();
+        let distinct_roles = IntGauge::new(
+            "cplane_distinct_roles",
+            "Distinct (endpoint, role) pairs requested so far",
+        )
+        .unwrap();
+        let wakes_in_flight =
+            IntGauge::new("cplane_wakes_in_flight", "wake_compute requests in flight").unwrap();
+        let wakes_in_flight_max = IntGauge::new(
+            "cplane_wakes_in_flight_max",
+            "Most wake_compute requests in flight at once",
+        )
+        .unwrap();
+
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry
+            .register(Box::new(request_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(distinct_endpoints.clone()))
+            .unwrap();
+        registry.register(Box::new(distinct_roles.clone())).unwrap();
+        registry
+            .register(Box::new(wakes_in_flight.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(wakes_in_flight_max.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            requests,
+            request_duration,
+            distinct_endpoints,
+            distinct_roles,
+            wakes_in_flight,
+            wakes_in_flight_max,
+            cache_ttl,
+            started: Instant::now(),
+            seen: Mutex::new(Seen::default()),
+        }
+    }
+
+    pub fn record_access_control(&self, endpoint: &str, role: &str) {
+        let now = Instant::now();
+        let ttl = Duration::from_secs(self.cache_ttl.project_info_secs);
+        let mut seen = self.seen.lock().unwrap();
+        seen.stats.access_control_requests += 1;
+        if seen.access_control_endpoints.insert(endpoint.to_owned()) {
+            self.distinct_endpoints
+                .with_label_values(&[route_label(Route::GetEndpointAccessControl)])
+                .inc();
+        }
+        match seen
+            .roles
+            .insert((endpoint.to_owned(), role.to_owned()), now)
+        {
+            None => self.distinct_roles.inc(),
+            Some(last) if now - last < ttl => seen.stats.access_control_refetched_within_ttl += 1,
+            Some(_) => {}
+        }
+    }
+
+    /// Counts the wake as in flight until the returned guard is dropped.
+    pub fn start_wake(&self, endpoint: &str) -> WakeGuard<'_> {
+        let now = Instant::now();
+        let ttl = Duration::from_secs(self.cache_ttl.wake_compute_secs);
+        let mut seen = self.seen.lock().unwrap();
+        seen.stats.wake_compute_requests += 1;
+        if !seen.wakes.contains_key(endpoint) {
+            self.distinct_endpoints
+                .with_label_values(&[route_label(Route::WakeCompute)])
+                .inc();
+        }
+        let wake = seen.wakes.entry(endpoint.to_owned()).or_default();
+        let concurrent = wake.in_flight > 0;
+        let refetched = wake.last_done.is_some_and(|last| now - last < ttl);
+        wake.in_flight += 1;
+        if concurrent {
+            seen.stats.wake_compute_concurrent_duplicates += 1;
+        } else if refetched {
+            seen.stats.wake_compute_refetched_within_ttl += 1;
+        }
+
+        // increments happen under the lock, so the max can't be raced past
+        self.wakes_in_flight.inc();
+        let in_flight = self.wakes_in_flight.get();
+        if in_flight > self.wakes_in_flight_max.get() {
+            self.wakes_in_flight_max.set(in_flight);
+        }
+        WakeGuard {
+            metrics: self,
+            endpoint: endpoint.to_owned(),
+        }
+    }
+}
+
+pub struct WakeGuard<'a> {
+    metrics: &'a Metrics,
+    endpoint: String,
+}
+
+impl Drop for WakeGuard<'_> {
+    fn drop(&mut self) {
+        let mut seen = self.metrics.seen.lock().unwrap();
+        if let Some(wake) = seen.wakes.get_mut(&self.endpoint) {
+            wake.in_flight -= 1;
+            wake.last_done = Some(Instant::now());
+        }
+        self.metrics.wakes_in_flight.dec();
+    }
+}
+
+fn route_label(route: Route) -> &'static str {
+    match route {
+        Route::GetEndpointAccessControl => "get_endpoint_access_control",
+        Route::WakeCompute => "wake_compute",
+    }
+}
+
+/// Middleware counting every request and its latency by matched route and response status.
+/// Prometheus scrapes aren't counted.
+pub async fn track(state: State<Context>, req: Request, next: Next) -> Response {
+    let path = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unknown", MatchedPath::as_str);
+    if path == "/metrics" {
+        return next.run(req).await;
+    }
+    let route = path_label(path).to_owned();
+
+    let start = Instant::now();
+    let response = next.run(req).await;
+
+    let status = response.status();
+    let labels = [route.as_str(), status.as_str()];
+    state.metrics.requests.with_label_values(&labels).inc();
+    state
+        .metrics
+        .request_duration
+        .with_label_values(&labels)
+        .observe(start.elapsed().as_secs_f64());
+
+    response
+}
+
+/// The matched path without the leading `/`, or without `/proxy/api/v1/` for the proxy's routes,
+/// e.g. `wake_compute` or `admin/endpoints/:endpoint`.
+fn path_label(path: &str) -> &str {
+    path.strip_prefix("/proxy/api/v1/")
+        .unwrap_or(path)
+        .trim_start_matches('/')
+}
+
+pub async fn metrics(state: State<Context>) -> impl IntoResponse {
+    TextEncoder::new()
+        .encode_to_string(&state.metrics.registry.gather())
+        .unwrap()
+}
+
+#[derive(Serialize)]
+pub struct StatsResponse {
+    uptime_secs: f64,
+    cache_ttl_secs: CacheTtlSecs,
+    #[serde(flatten)]
+    stats: Stats,
+    distinct_roles: u64,
+    distinct_wake_compute_endpoints: u64,
+    wakes_in_flight_max: i64,
+}
+
+#[derive(Serialize)]
+struct CacheTtlSecs {
+    project_info: u64,
+    wake_compute: u64,
+}
+
+/// Counts the proxy's repeated requests, to work out its cache hit and coalescing rates.
+pub async fn stats(state: State<Context>) -> Json<StatsResponse> {
+    let metrics = &state.metrics;
+    let seen = metrics.seen.lock().unwrap();
+    Json(StatsResponse {
+        uptime_secs: metrics.started.elapsed().as_secs_f64(),
+        cache_ttl_secs: CacheTtlSecs {
+            project_info: metrics.cache_ttl.project_info_secs,
+            wake_compute: metrics.cache_ttl.wake_compute_secs,
+        },
+        stats: seen.stats.clone(),
+        distinct_roles: seen.roles.len() as u64,
+        distinct_wake_compute_endpoints: seen.wakes.len() as u64,
+        wakes_in_flight_max: metrics.wakes_in_flight_max.get(),
+    })
+}
@@ -0,0 +1,118 @@
+use std::error::Error;
+
+use serde_json::json;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::mpsc,
+};
+
+/// The pub/sub channel the proxy subscribes to for cache invalidation.
+const CHANNEL: &str = "neondb-proxy-ws-updates";
+
+/// Publishes the control plane's cache invalidation messages to Redis at `$REDIS_NOTIFICATIONS_ADDR`.
+pub struct Notifier {
+    tx: mpsc::UnboundedSender<String>,
+}
+
+impl Notifier {
+    pub fn new(addr: String) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(publish_all(addr, rx));
+        Self { tx }
+    }
+
+    pub fn password_updated(&self, project_id: &str, role_name: &str) {
+        self.send(
+            "/password_updated",
+            json!({ "project_id": project_id, "role_name": role_name }),
+        );
+    }
+
+    pub fn allowed_ips_updated(&self, project_id: &str) {
+        self.send("/allowed_ips_updated", json!({ "project_id": project_id }));
+    }
+
+    pub fn allowed_vpc_endpoints_updated(&self, project_id: &str) {
+        self.send(
+            "/allowed_vpc_endpoints_updated_for_projects",
+            json!({ "project_ids": [project_id] }),
+        );
+    }
+
+    pub fn block_access_updated(&self, project_id: &str) {
+        self.send(
+            "/block_public_or_vpc_access_updated",
+            json!({ "project_id": project_id }),
+        );
+    }
+
+    /// Drops everything the proxy cached for the endpoint, e.g. after it was deleted or moved.
+    pub fn endpoint_settings_updated(&self, endpoint_id: &str) {
+        self.send(
+            "/endpoint_settings_update",
+            json!({ "endpoint_id": endpoint_id }),
+        );
+    }
+
+    fn send(&self, topic: &str, data: serde_json::Value) {
+        // the proxy expects `data` to be JSON encoded a second time
+        let message = json!({
+            "type": "message",
+            "topic": topic,
+            "data": data.to_string(),
+        });
+        // the publisher task only stops with the runtime
+        let _ = self.tx.send(message.to_string());
+    }
+}
+
+/// Publishes messages in order over one connection, reconnecting once if it broke.
+async fn publish_all(addr: String, mut rx: mpsc::UnboundedReceiver<String>) {
+    let mut conn = None;
+    while let Some(message) = rx.recv().await {
+        for _ in 0..2 {
+            let s = match &mut conn {
+                Some(s) => s,
+                None => match TcpStream::connect(&addr).await {
+                    Ok(s) => conn.insert(s),
+                    Err(e) => {
+                        println!("Could not connect to redis at {addr}: {e}");
+                        break;
+                    }
+                },
+            };
+            match publish(s, &message).await {
+                Ok(receivers) => {
+                    println!("Published {message} to {receivers} subscribers");
+                    break;
+                }
+                Err(e) => {
+                    println!("Could not publish to redis at {addr}: {e}");
+                    conn = None;
+                }
+            }
+        }
+    }
+}
+
+async fn publish(s: &mut TcpStream, message: &str) -> Result<i64, Box<dyn Error + Send + Sync>> {
+    let mut cmd = Vec::new();
+    cmd.extend_from_slice(b"*3\r\n$7\r\nPUBLISH\r\n");
+    for arg in [CHANNEL, message] {
+        cmd.extend_from_slice(format!("${}\r\n{arg}\r\n", arg.len()).as_bytes());
+    }
+    s.write_all(&cmd).await?;
+
+    let mut reply = Vec::new();
+    while !reply.ends_with(b"\r\n") {
+        if s.read_buf(&mut reply).await? == 0 {
+            return Err("eof".into());
+        }
+    }
+    let reply = std::str::from_utf8(&reply)?.trim_end();
+    match reply.strip_prefix(':') {
+        Some(n) => Ok(n.parse()?),
+        None => Err(format!("unexpected reply {reply}").into()),
+    }
+}
@@ -0,0 +1,337 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{BufWriter, Write},
+    sync::{mpsc, Mutex, OnceLock},
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::Request,
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{error::ControlPlaneError, scenario::Route};
+
+/// One control plane call, as written to `$CPLANE_RECORD` and read from `$CPLANE_REPLAY`.
+#[derive(Serialize, Deserialize)]
+pub struct Exchange {
+    pub timestamp_ms: u64,
+    pub route: Route,
+    pub endpoint: String,
+    pub role: Option<String>,
+    pub application_name: Option<String>,
+    pub session_id: Option<String>,
+    pub status: u16,
+    pub response: Value,
+    /// Time spent answering, including simulated cold starts and the delays added by limits and
+    /// latency faults.
+    pub latency_ms: f64,
+}
+
+/// The request fields an exchange is recorded with.
+pub struct Call<'a> {
+    pub route: Route,
+    pub endpoint: &'a str,
+    pub role: Option<&'a str>,
+    pub application_name: Option<&'a str>,
+    pub session_id: Option<&'a str>,
+}
+
+/// Appends every exchange to a JSONL file.
+///
+/// Lines are written by a thread of its own, so slow disks don't hold up the runtime.
+pub struct Recorder {
+    lines: Option<mpsc::Sender<Vec<u8>>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl Recorder {
+    pub fn new(path: &str) -> Self {
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap_or_else(|e| panic!("could not open recording {path}: {e}"));
+        println!("Recording control plane calls to {path}");
+        let (lines, rx) = mpsc::channel();
+        let writer = std::thread::spawn(move || write_lines(file, rx));
+        Self {
+            lines: Some(lines),
+            writer: Some(writer),
+        }
+    }
+
+    fn record(&self, exchange: &Exchange) {
+        let mut line = serde_json::to_vec(exchange).unwrap();
+        line.push(b'\n');
+        if let Some(lines) = &self.lines {
+            let _ = lines.send(line);
+        }
+    }
+}
+
+/// Writes lines until the recorder is dropped, flushing whenever it catches up.
+fn write_lines(file: File, rx: mpsc::Receiver<Vec<u8>>) {
+    let mut file = BufWriter::new(file);
+    while let Ok(line) = rx.recv() {
+        let mut res = file.write_all(&line);
+        while let Ok(line) = rx.try_recv() {
+            res = res.and_then(|_| file.write_all(&line));
+        }
+        if let Err(e) = res.and_then(|_| file.flush()) {
+            println!("Could not write recording: {e}");
+        }
+    }
+}
+
+impl Drop for Recorder {
+    /// Waits for the lines still queued to be written.
+    fn drop(&mut self) {
+        self.lines = None;
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// When a request reached cplane-mock, before limits and faults delayed it.
+#[derive(Clone, Copy)]
+pub struct Received(pub Instant);
+
+/// Middleware stamping requests with `Received`, outside the limit and fault middlewares.
+pub async fn received(mut req: Request, next: Next) -> Response {
+    req.extensions_mut().insert(Received(Instant::now()));
+    next.run(req).await
+}
+
+/// Longest latency a recording may hold, a day.
+const MAX_LATENCY_MS: f64 = 24.0 * 60.0 * 60.0 * 1000.0;
+
+/// (route, endpoint, role)
+type Key = (Route, String, Option<String>);
+
+/// Serves recorded responses on the recorded timeline.
+///
+/// The timeline starts with the first call replayed. Each response goes out as long after that as
+/// it went out after the first recorded call, and at least its recorded latency after the call
+/// came in. Responses go out in the recorded order as long as the proxy's calls come in no later
+/// than they were recorded. Calls are matched to responses by (route, endpoint, role), in order.
+///
+/// Calls the recording has no more answers for are handled as usual.
+pub struct Replayer {
+    exchanges: Mutex<HashMap<Key, VecDeque<Exchange>>>,
+    /// When the first recorded call came in, in milliseconds since the epoch.
+    first_call_ms: f64,
+    /// When the first call was replayed.
+    started: OnceLock<Instant>,
+}
+
+impl Replayer {
+    pub fn new(path: &str) -> Self {
+        let data = std::fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("could not read recording {path}: {e}"));
+        let mut recorded = Vec::new();
+        for (i, line) in data.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let exchange: Exchange = serde_json::from_str(line)
+                .unwrap_or_else(|e| panic!("invalid recording {path} line {}: {e}", i + 1));
+            if !(0.0..=MAX_LATENCY_MS).contains(&exchange.latency_ms) {
+                panic!(
+                    "invalid recording {path} line {}: latency_ms {} is not within 0..={MAX_LATENCY_MS}",
+                    i + 1,
+                    exchange.latency_ms
+                );
+            }
+            recorded.push(exchange);
+        }
+        // lines are written as responses complete, which is nearly but not exactly in order
+        recorded.sort_by_key(|exchange| exchange.timestamp_ms);
+        let first_call_ms = recorded
+            .iter()
+            .map(Exchange::called_ms)
+            .min_by(f64::total_cmp)
+            .unwrap_or(0.0);
+
+        println!(
+            "Replaying {} control plane calls from {path}",
+            recorded.len()
+        );
+        let mut exchanges: HashMap<_, VecDeque<_>> = HashMap::new();
+        for exchange in recorded {
+            exchanges
+                .entry((
+                    exchange.route,
+                    exchange.endpoint.clone(),
+                    exchange.role.clone(),
+                ))
+                .or_default()
+                .push_back(exchange);
+        }
+        Self {
+            exchanges: Mutex::new(exchanges),
+            first_call_ms,
+            started: OnceLock::new(),
+        }
+    }
+
+    /// Answers with the next recorded response for the call, when it is due on the timeline.
+    pub async fn replay(&self, call: &Call<'_>, received: Instant) -> Option<Response> {
+        let key = (
+            call.route,
+            call.endpoint.to_owned(),
+            call.role.map(str::to_owned),
+        );
+        let exchange = self.exchanges.lock().unwrap().get_mut(&key)?.pop_front()?;
+
+        let started = *self.started.get_or_init(|| received);
+        let offset = exchange.timestamp_ms as f64 - self.first_call_ms;
+        let latency = Duration::from_secs_f64(exchange.latency_ms / 1000.0);
+        let due = started
+            .checked_add(Duration::from_secs_f64(offset / 1000.0))
+            .unwrap_or(received)
+            .max(received + latency);
+        tokio::time::sleep_until(due.into()).await;
+
+        let status = StatusCode::from_u16(exchange.status).unwrap_or(StatusCode::OK);
+        Some((status, Json(exchange.response)).into_response())
+    }
+}
+
+impl Exchange {
+    /// When the call came in, in milliseconds since the epoch.
+    fn called_ms(&self) -> f64 {
+        self.timestamp_ms as f64 - self.latency_ms
+    }
+}
+
+/// Turns a handler's result into its response, recording the exchange if enabled.
+pub fn respond<T: Serialize>(
+    recorder: Option<&Recorder>,
+    call: Call<'_>,
+    started: Instant,
+    result: Result<T, ControlPlaneError>,
+) -> Response {
+    let Some(recorder) = recorder else {
+        return match result {
+            Ok(body) => Json(body).into_response(),
+            Err(err) => err.into_response(),
+        };
+    };
+
+    let (status, response) = match result {
+        Ok(body) => (StatusCode::OK, serde_json::to_value(body).unwrap()),
+        Err(err) => (err.http_status_code(), serde_json::to_value(&err).unwrap()),
+    };
+    let exchange = Exchange {
+        timestamp_ms: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_millis() as u64,
+        route: call.route,
+        endpoint: call.endpoint.to_owned(),
+        role: call.role.map(str::to_owned),
+        application_name: call.application_name.map(str::to_owned),
+        session_id: call.session_id.map(str::to_owned),
+        status: status.as_u16(),
+        response,
+        latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+    };
+    recorder.record(&exchange);
+    (status, Json(exchange.response)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic;
+
+    use super::*;
+
+    fn exchange(timestamp_ms: u64, route: &str, endpoint: &str, latency_ms: &str) -> String {
+        format!(
+            r#"{{"timestamp_ms":{timestamp_ms},"route":"{route}","endpoint":"{endpoint}","role":null,"application_name":null,"session_id":null,"status":200,"response":{{"endpoint":"{endpoint}"}},"latency_ms":{latency_ms}}}"#
+        )
+    }
+
+    /// Writes a recording to a file of its own.
+    fn recording(name: &str, lines: &[String]) -> String {
+        let path =
+            std::env::temp_dir().join(format!("cplane-mock-{}-{name}.jsonl", std::process::id()));
+        std::fs::write(&path, lines.join("\n")).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    #[tokio::test]
+    async fn replays_on_the_recorded_timeline() {
+        // ep-2 was answered quickly, but only after ep-1, and ep-3 only ever came up later
+        let path = recording(
+            "timeline",
+            &[
+                exchange(1050, "wake_compute", "ep-2", "10"),
+                exchange(1000, "wake_compute", "ep-1", "100"),
+                exchange(1300, "wake_compute", "ep-3", "5"),
+            ],
+        );
+        let replayer = Replayer::new(&path);
+        let call = |endpoint| Call {
+            route: Route::WakeCompute,
+            endpoint,
+            role: None,
+            application_name: None,
+            session_id: None,
+        };
+        let replay = |endpoint: &'static str, received| {
+            let replayer = &replayer;
+            async move {
+                replayer.replay(&call(endpoint), received).await.unwrap();
+                (endpoint, received.elapsed())
+            }
+        };
+
+        let received = Instant::now();
+        let (first, second) = tokio::join!(replay("ep-2", received), replay("ep-1", received));
+        assert!(first.1 >= Duration::from_millis(150), "{first:?}");
+        assert!(second.1 >= Duration::from_millis(100), "{second:?}");
+        assert!(second.1 < first.1, "ep-1 is answered before ep-2");
+
+        // a call coming in late still waits for its recorded latency
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let (_, elapsed) = replay("ep-3", Instant::now()).await;
+        assert!(elapsed >= Duration::from_millis(5), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(100), "{elapsed:?}");
+
+        // nothing left to replay
+        assert!(replayer
+            .replay(&call("ep-1"), Instant::now())
+            .await
+            .is_none());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn rejects_invalid_latencies() {
+        for latency in ["-1", "1e300", "86400001"] {
+            let path = recording(
+                "invalid",
+                &[
+                    exchange(1000, "wake_compute", "ep-1", "10"),
+                    exchange(1000, "wake_compute", "ep-1", latency),
+                ],
+            );
+            let Err(err) = panic::catch_unwind(|| Replayer::new(&path)) else {
+                panic!("latency_ms {latency} was accepted");
+            };
+            let message = err.downcast_ref::<String>().unwrap();
+            assert!(message.contains("line 2: latency_ms"), "{message}");
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
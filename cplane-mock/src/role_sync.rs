@@ -0,0 +1,95 @@
+use std::{error::Error, time::Duration};
+
+use tokio::sync::Mutex;
+use tokio_postgres::{Config, NoTls};
+
+use crate::credentials::Secret;
+
+/// Pushes role secrets to the computes with `ALTER ROLE`, like the control plane does when a
+/// password changes, so the compute checks the proxy's keys against the secret cplane-mock
+/// handed out.
+///
+/// Enabled by `$COMPUTE_ADMIN_USER`, which connects to every address of the compute pool with
+/// `$COMPUTE_ADMIN_PASSWORD` (`password` by default).
+pub struct RoleSync {
+    addresses: Vec<String>,
+    user: String,
+    password: String,
+    /// Held for the whole push, so an older secret never overwrites a newer one.
+    lock: Mutex<()>,
+}
+
+impl RoleSync {
+    pub fn from_env(addresses: &[String]) -> Option<Self> {
+        let user = std::env::var("COMPUTE_ADMIN_USER")
+            .ok()
+            .filter(|user| !user.is_empty())?;
+        let password =
+            std::env::var("COMPUTE_ADMIN_PASSWORD").unwrap_or_else(|_| "password".to_owned());
+        println!("Pushing role secrets to the computes as {user}");
+        Some(Self {
+            addresses: addresses.to_vec(),
+            user,
+            password,
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// Sets the secrets on every compute. `roles` is called once the previous push is done,
+    /// so it sees the latest secrets.
+    pub async fn push(&self, roles: impl FnOnce() -> Vec<(String, Secret)>) -> Result<(), String> {
+        let _guard = self.lock.lock().await;
+        let roles = roles();
+        for address in &self.addresses {
+            self.push_to(address, &roles)
+                .await
+                .map_err(|e| format!("{address}: {e}"))?;
+        }
+        Ok(())
+    }
+
+    /// Pushes the secrets until every compute took them, as the computes may still be starting.
+    pub async fn push_until_done(&self, roles: impl Fn() -> Vec<(String, Secret)>) {
+        while let Err(e) = self.push(&roles).await {
+            println!("Could not push role secrets to the computes, retrying: {e}");
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        println!("Pushed role secrets to the computes");
+    }
+
+    async fn push_to(
+        &self,
+        address: &str,
+        roles: &[(String, Secret)],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (host, port) = address
+            .rsplit_once(':')
+            .ok_or("compute address is not host:port")?;
+        let (client, connection) = Config::new()
+            .host(host)
+            .port(port.parse()?)
+            .user(&self.user)
+            .password(&self.password)
+            .dbname("postgres")
+            .connect_timeout(Duration::from_secs(5))
+            .connect(NoTls)
+            .await?;
+        tokio::spawn(connection);
+        for (role, secret) in roles {
+            client.batch_execute(&alter_role(role, secret)).await?;
+        }
+        Ok(())
+    }
+}
+
+/// `ALTER ROLE` with the role as a quoted identifier, so any name works.
+fn alter_role(role: &str, secret: &Secret) -> String {
+    let role = role.replace('"', "\"\"");
+    match secret {
+        Secret::Scram(secret) | Secret::Md5(secret) => {
+            let secret = secret.replace('\'', "''");
+            format!("ALTER ROLE \"{role}\" WITH PASSWORD '{secret}'")
+        }
+        Secret::None => format!("ALTER ROLE \"{role}\" WITH PASSWORD NULL"),
+    }
+}
@@ -0,0 +1,271 @@
+use std::{collections::BTreeMap, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cold_start::ColdStart,
+    compute::{Lifecycle, Migration},
+    console_redirect::ConsoleRedirect,
+    credentials::{validate_salt, RoleConfig, DEFAULT_SCRAM_ITERATIONS},
+    error::{ControlPlaneError, ErrorRule},
+    fault::FaultRule,
+    hierarchy::{Hierarchy, Ids},
+    jwt::JwtConfig,
+    limit::Limits,
+    metrics::CacheTtl,
+};
+
+/// Describes how cplane-mock should answer for each endpoint.
+///
+/// Loaded from the file at `$CPLANE_SCENARIO` (TOML, or JSON if the file ends with `.json`).
+/// Without a scenario every endpoint gets the allow-all answer.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    #[serde(default)]
+    pub endpoints: Vec<EndpointRule>,
+    /// Cold start model for endpoints whose rule doesn't override it.
+    #[serde(default)]
+    pub cold_start: ColdStart,
+    /// Without it every wake samples the cold start model independently.
+    pub lifecycle: Option<Lifecycle>,
+    #[serde(default)]
+    pub migrations: Vec<Migration>,
+    /// Checked in order, the first rule that fires answers the request.
+    #[serde(default)]
+    pub errors: Vec<ErrorRule>,
+    /// Transport faults, checked in order.
+    #[serde(default)]
+    pub faults: Vec<FaultRule>,
+    /// Enables JWT auth rules and the token signing routes.
+    pub jwt: Option<JwtConfig>,
+    pub limits: Option<Limits>,
+    /// The proxy's cache TTLs, for `/stats`.
+    #[serde(default)]
+    pub cache_ttl: CacheTtl,
+    pub hierarchy: Option<Hierarchy>,
+    #[serde(default)]
+    pub console_redirect: ConsoleRedirect,
+    /// PBKDF2 iterations of SCRAM secrets, overridden by `$CPLANE_SCRAM_ITERATIONS`.
+    scram_iterations: Option<u32>,
+    /// Base64 salt of SCRAM secrets whose role doesn't set one, overridden by `$CPLANE_SCRAM_SALT`.
+    /// Without it every secret gets a random salt.
+    scram_salt: Option<String>,
+}
+
+/// The control plane routes the proxy calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Route {
+    GetEndpointAccessControl,
+    WakeCompute,
+}
+
+/// Access control settings for all endpoints matching `endpoint`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EndpointRule {
+    /// Endpoint id, or a glob where `*` matches any run of characters and `?` any single one.
+    pub endpoint: String,
+    /// IPs, CIDRs or ranges, passed through to the proxy as is. Empty allows everything.
+    #[serde(default)]
+    pub allowed_ips: Vec<String>,
+    #[serde(default)]
+    pub allowed_vpc_endpoint_ids: Vec<String>,
+    #[serde(default)]
+    pub block_public_connections: bool,
+    #[serde(default)]
+    pub block_vpc_connections: bool,
+    pub account_id: Option<String>,
+    /// Role name to password. Without it, any role is accepted with the password `password`.
+    pub roles: Option<BTreeMap<String, RoleConfig>>,
+    /// SCRAM iterations for this rule's roles, instead of the scenario's.
+    pub scram_iterations: Option<u32>,
+    pub cold_start: Option<ColdStart>,
+}
+
+impl Scenario {
+    /// `role_sync` tells whether role secrets are pushed to the computes, see [`RoleSync`].
+    ///
+    /// [`RoleSync`]: crate::role_sync::RoleSync
+    pub fn from_env(role_sync: bool) -> Self {
+        let mut scenario = match std::env::var("CPLANE_SCENARIO") {
+            Ok(path) => Self::load(Path::new(&path), role_sync),
+            Err(_) => Self::default(),
+        };
+        // lets runs sweep the hashing cost without editing the scenario
+        if let Ok(iterations) = std::env::var("CPLANE_SCRAM_ITERATIONS") {
+            let iterations = iterations
+                .parse()
+                .ok()
+                .filter(|&n| n > 0)
+                .unwrap_or_else(|| panic!("invalid CPLANE_SCRAM_ITERATIONS {iterations}"));
+            scenario.scram_iterations = Some(iterations);
+        }
+        // lets postgres-mock derive the same secrets, see `$POSTGRES_MOCK_SCRAM_SALT`
+        if let Ok(salt) = std::env::var("CPLANE_SCRAM_SALT") {
+            validate_salt(&salt).unwrap_or_else(|e| panic!("invalid CPLANE_SCRAM_SALT: {e}"));
+            scenario.scram_salt = Some(salt);
+        }
+        scenario
+    }
+
+    fn load(path: &Path, role_sync: bool) -> Self {
+        let data = std::fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("could not read scenario {}: {e}", path.display()));
+        let scenario: Self = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(&data)
+                .unwrap_or_else(|e| panic!("invalid scenario {}: {e}", path.display()))
+        } else {
+            toml::from_str(&data)
+                .unwrap_or_else(|e| panic!("invalid scenario {}: {e}", path.display()))
+        };
+        scenario
+            .validate(role_sync)
+            .unwrap_or_else(|e| panic!("invalid scenario {}: {e}", path.display()));
+        println!(
+            "Loaded scenario {} with {} endpoint rules",
+            path.display(),
+            scenario.endpoints.len()
+        );
+        scenario
+    }
+
+    fn validate(&self, role_sync: bool) -> Result<(), String> {
+        self.cold_start.validate()?;
+        if self.scram_iterations == Some(0) {
+            return Err("scram_iterations must be positive".to_owned());
+        }
+        if let Some(salt) = &self.scram_salt {
+            validate_salt(salt).map_err(|e| format!("scram_salt: {e}"))?;
+        }
+        self.console_redirect.validate()?;
+        if let Some(hierarchy) = &self.hierarchy {
+            hierarchy.validate()?;
+        }
+        if let Some(limits) = &self.limits {
+            limits.validate()?;
+        }
+        for rule in &self.errors {
+            rule.validate()?;
+        }
+        for rule in &self.faults {
+            rule.validate()?;
+        }
+        for rule in &self.endpoints {
+            if rule.scram_iterations == Some(0) {
+                return Err(format!(
+                    "endpoint {}: scram_iterations must be positive",
+                    rule.endpoint
+                ));
+            }
+            // computes derive the password of roles not pushed to them with their own parameters
+            if rule.scram_iterations.is_some() && (rule.roles.is_none() || !role_sync) {
+                return Err(format!(
+                    "endpoint {}: scram_iterations needs roles and $COMPUTE_ADMIN_USER, so the computes get the secrets",
+                    rule.endpoint
+                ));
+            }
+            for (name, role) in rule.roles.iter().flatten() {
+                role.validate()
+                    .map_err(|e| format!("endpoint {} role {name}: {e}", rule.endpoint))?;
+                if role.has_scram_params() && !role_sync {
+                    return Err(format!(
+                        "endpoint {} role {name}: scram_iterations and salt need $COMPUTE_ADMIN_USER, so the computes get the secret",
+                        rule.endpoint
+                    ));
+                }
+            }
+            if let Some(cold_start) = &rule.cold_start {
+                cold_start
+                    .validate()
+                    .map_err(|e| format!("endpoint {}: {e}", rule.endpoint))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn scram_iterations(&self) -> u32 {
+        self.scram_iterations.unwrap_or(DEFAULT_SCRAM_ITERATIONS)
+    }
+
+    pub fn scram_salt(&self) -> Option<&str> {
+        self.scram_salt.as_deref()
+    }
+
+    /// SCRAM iterations for new secrets of the endpoint's roles.
+    pub fn endpoint_scram_iterations(&self, endpoint: &str) -> u32 {
+        self.endpoint(endpoint)
+            .and_then(|rule| rule.scram_iterations)
+            .unwrap_or_else(|| self.scram_iterations())
+    }
+
+    /// Returns the first rule matching the endpoint, in file order.
+    pub fn endpoint(&self, endpoint: &str) -> Option<&EndpointRule> {
+        self.endpoint_index(endpoint).map(|i| &self.endpoints[i])
+    }
+
+    /// Project, branch and account of the endpoint. An `account_id` in its rule wins.
+    pub fn ids(&self, endpoint: &str) -> Ids {
+        let ids = Ids::new(self.hierarchy.as_ref(), endpoint);
+        Ids {
+            account_id: self
+                .endpoint(endpoint)
+                .and_then(|r| r.account_id.clone())
+                .or(ids.account_id),
+            ..ids
+        }
+    }
+
+    pub fn cold_start(&self, endpoint: &str) -> &ColdStart {
+        self.endpoint(endpoint)
+            .and_then(|rule| rule.cold_start.as_ref())
+            .unwrap_or(&self.cold_start)
+    }
+
+    /// Returns the error to answer with instead of the regular response, if any.
+    pub fn injected_error(&self, route: Route, endpoint: &str) -> Option<ControlPlaneError> {
+        self.errors
+            .iter()
+            .find_map(|rule| rule.fire(route, endpoint))
+    }
+
+    pub fn endpoint_index(&self, endpoint: &str) -> Option<usize> {
+        self.endpoints
+            .iter()
+            .position(|rule| glob_match(rule.endpoint.as_bytes(), endpoint.as_bytes()))
+    }
+}
+
+pub fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => s.is_empty(),
+        Some((b'*', rest)) => (0..=s.len()).any(|i| glob_match(rest, &s[i..])),
+        Some((b'?', rest)) => !s.is_empty() && glob_match(rest, &s[1..]),
+        Some((c, rest)) => s.first() == Some(c) && glob_match(rest, &s[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scram_params_need_role_sync() {
+        let scenario = |rule: &str| -> Scenario {
+            toml::from_str(&format!("[[endpoints]]\nendpoint = \"ep\"\n{rule}")).unwrap()
+        };
+        let per_role = scenario("roles = { demo = { password = \"x\", salt = \"c2FsdA==\" } }");
+        assert!(per_role.validate(false).is_err());
+        assert!(per_role.validate(true).is_ok());
+
+        let per_rule = scenario("scram_iterations = 10000\nroles = { demo = \"x\" }");
+        assert!(per_rule.validate(false).is_err());
+        assert!(per_rule.validate(true).is_ok());
+
+        let any_role = scenario("scram_iterations = 10000");
+        assert!(any_role.validate(true).is_err());
+
+        assert!(scenario("roles = { demo = \"x\" }").validate(false).is_ok());
+    }
+}
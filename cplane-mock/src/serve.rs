@@ -0,0 +1,101 @@
+use std::{pin::pin, sync::Arc};
+
+use axum::{extract::Request, Router};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use rustls::ServerConfig;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::{watch, Notify},
+    task::JoinSet,
+};
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+
+/// Drops the connection a request came in on, without writing anything more to it.
+///
+/// Every request `serve` hands to the app carries one in its extensions.
+#[derive(Clone)]
+pub struct CloseConnection(Arc<Notify>);
+
+impl CloseConnection {
+    pub fn close(&self) {
+        self.0.notify_one();
+    }
+}
+
+/// Serves HTTP/1.1 and h2, over TLS if `tls` is set, until `shutdown` completes, then lets open
+/// connections finish their requests.
+pub async fn serve(
+    listener: TcpListener,
+    tls: Option<ServerConfig>,
+    app: Router,
+    shutdown: impl std::future::Future<Output = ()>,
+) {
+    let acceptor = tls.map(|config| TlsAcceptor::from(Arc::new(config)));
+    let (stop, stopped) = watch::channel(());
+    let mut connections = JoinSet::new();
+    let mut shutdown = pin!(shutdown);
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    println!("Could not accept connection: {e}");
+                    continue;
+                }
+            },
+            // reap finished connections so the set doesn't grow with every one
+            Some(_) = connections.join_next() => continue,
+            _ = &mut shutdown => break,
+        };
+
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        let stopped = stopped.clone();
+        connections.spawn(async move {
+            let Some(acceptor) = acceptor else {
+                // plain HTTP still serves h2c to clients that speak it
+                return serve_connection(stream, app, stopped).await;
+            };
+            match acceptor.accept(stream).await {
+                Ok(stream) => serve_connection(stream, app, stopped).await,
+                Err(e) => println!("TLS handshake with {peer} failed: {e}"),
+            }
+        });
+    }
+
+    let _ = stop.send(());
+    while connections.join_next().await.is_some() {}
+}
+
+async fn serve_connection<S>(stream: S, app: Router, mut stopped: watch::Receiver<()>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let close = Arc::new(Notify::new());
+    let extension = CloseConnection(close.clone());
+    let app = app.map_request(move |mut req: Request<Incoming>| {
+        req.extensions_mut().insert(extension.clone());
+        req
+    });
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let mut conn =
+        pin!(builder.serve_connection(TokioIo::new(stream), TowerToHyperService::new(app)));
+    // returning drops the connection, closing the socket
+    tokio::select! {
+        _ = conn.as_mut() => return,
+        _ = close.notified() => return,
+        _ = stopped.changed() => conn.as_mut().graceful_shutdown(),
+    }
+    tokio::select! {
+        _ = conn => {}
+        _ = close.notified() => {}
+    }
+}
@@ -0,0 +1,12 @@
+use rustls::ServerConfig;
+
+/// Serves the certificate chain and key from the PEM files, negotiating h2 or HTTP/1.1.
+pub fn config(cert_path: &str, key_path: &str) -> ServerConfig {
+    let (certs, key) = mock_tls::load_pem(cert_path, key_path);
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .expect("certificate and key must match");
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    config
+}
@@ -0,0 +1,375 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use rand::{thread_rng, Rng};
+use tokio::{
+    net::UdpSocket,
+    select,
+    signal::unix::{signal, SignalKind},
+};
+
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+
+const RCODE_SERVFAIL: u8 = 2;
+const RCODE_NOTIMP: u8 = 4;
+const RCODE_REFUSED: u8 = 5;
+
+/// From the `$DNS_MOCK_*` variables.
+struct Config {
+    /// Lowercase, without the trailing dot.
+    zone: String,
+    answer: Ipv4Addr,
+    ttl: u32,
+    latency: Duration,
+    /// Up to this much is added to `latency`, uniformly.
+    jitter: Duration,
+    servfail_percent: f64,
+    /// Queries left unanswered, so the client times out.
+    drop_percent: f64,
+}
+
+impl Config {
+    fn from_env() -> Self {
+        let config = Self {
+            zone: env("DNS_MOCK_ZONE", "local".to_owned())
+                .trim_end_matches('.')
+                .to_ascii_lowercase(),
+            answer: env("DNS_MOCK_ANSWER", Ipv4Addr::LOCALHOST),
+            ttl: env("DNS_MOCK_TTL", 60),
+            latency: Duration::from_millis(env("DNS_MOCK_LATENCY_MS", 0)),
+            jitter: Duration::from_millis(env("DNS_MOCK_LATENCY_JITTER_MS", 0)),
+            servfail_percent: env("DNS_MOCK_SERVFAIL_PERCENT", 0.0),
+            drop_percent: env("DNS_MOCK_DROP_PERCENT", 0.0),
+        };
+        config
+            .validate()
+            .unwrap_or_else(|e| panic!("invalid config: {e}"));
+        config
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        for (name, percent) in [
+            ("DNS_MOCK_SERVFAIL_PERCENT", self.servfail_percent),
+            ("DNS_MOCK_DROP_PERCENT", self.drop_percent),
+        ] {
+            if !(0.0..=100.0).contains(&percent) {
+                return Err(format!("{name} {percent} is not within 0..=100"));
+            }
+        }
+        Ok(())
+    }
+
+    fn in_zone(&self, name: &str) -> bool {
+        name == self.zone
+            || name
+                .strip_suffix(self.zone.as_str())
+                .is_some_and(|prefix| prefix.ends_with('.'))
+    }
+}
+
+fn env<T: FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("invalid {name} {value}")),
+        Err(_) => default,
+    }
+}
+
+/// Answers A queries for every name in one zone with the same address, so cplane-mock can hand
+/// out compute hostnames. Names outside the zone are refused.
+#[tokio::main]
+async fn main() {
+    let mut signal = signal(SignalKind::terminate()).unwrap();
+    let addr = env("DNS_MOCK_ADDR", "0.0.0.0:53".to_owned());
+    let socket = Arc::new(UdpSocket::bind(&addr).await.unwrap());
+    let config = Arc::new(Config::from_env());
+    println!(
+        "Answering *.{} with {} on {addr}",
+        config.zone, config.answer
+    );
+
+    let mut buf = [0; 512];
+    loop {
+        select! {
+            received = socket.recv_from(&mut buf) => {
+                let (n, peer) = match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        println!("Could not receive query: {e}");
+                        continue;
+                    }
+                };
+                let query = buf[..n].to_vec();
+                tokio::spawn(answer(socket.clone(), config.clone(), query, peer));
+            }
+            _ = signal.recv() => break,
+        };
+    }
+}
+
+async fn answer(socket: Arc<UdpSocket>, config: Arc<Config>, query: Vec<u8>, peer: SocketAddr) {
+    let (drop, delay, servfail) = {
+        let mut rng = thread_rng();
+        (
+            rng.gen_bool(config.drop_percent / 100.0),
+            config.latency + config.jitter.mul_f64(rng.gen()),
+            rng.gen_bool(config.servfail_percent / 100.0),
+        )
+    };
+    if drop {
+        return;
+    }
+
+    let Some(response) = respond(&config, &query, servfail) else {
+        return;
+    };
+    tokio::time::sleep(delay).await;
+    if let Err(e) = socket.send_to(&response, peer).await {
+        println!("Could not answer {peer}: {e}");
+    }
+}
+
+/// Builds the response to a query, or `None` if it isn't worth answering.
+fn respond(config: &Config, query: &[u8], servfail: bool) -> Option<Vec<u8>> {
+    let header = query.get(..12)?;
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    let is_response = flags & 0x8000 != 0;
+    let opcode = (flags >> 11) & 0xf;
+    let qdcount = u16::from_be_bytes([header[4], header[5]]);
+    if is_response {
+        return None;
+    }
+
+    let question = if opcode == 0 && qdcount == 1 {
+        parse_question(&query[12..])
+    } else {
+        None
+    };
+    let Some((name, qtype, qclass, question)) = question else {
+        return Some(reply(header, &[], RCODE_NOTIMP, 0, &[]));
+    };
+
+    if servfail {
+        return Some(reply(header, question, RCODE_SERVFAIL, 0, &[]));
+    }
+    if !config.in_zone(&name) {
+        return Some(reply(header, question, RCODE_REFUSED, 0, &[]));
+    }
+    if qtype != TYPE_A || qclass != CLASS_IN {
+        // the name exists but has no records of this type
+        return Some(reply(header, question, 0, 0, &[]));
+    }
+
+    let mut record = Vec::with_capacity(16);
+    // pointer to the name in the question, right after the header
+    record.extend_from_slice(&0xc00cu16.to_be_bytes());
+    record.extend_from_slice(&TYPE_A.to_be_bytes());
+    record.extend_from_slice(&CLASS_IN.to_be_bytes());
+    record.extend_from_slice(&config.ttl.to_be_bytes());
+    record.extend_from_slice(&4u16.to_be_bytes());
+    record.extend_from_slice(&config.answer.octets());
+    Some(reply(header, question, 0, 1, &record))
+}
+
+/// Returns the lowercase name, type, class and raw bytes of the question.
+fn parse_question(data: &[u8]) -> Option<(String, u16, u16, &[u8])> {
+    let mut labels = Vec::new();
+    let mut i = 0;
+    loop {
+        let len = *data.get(i)? as usize;
+        i += 1;
+        if len == 0 {
+            break;
+        }
+        // compression pointers and extended labels don't appear in questions
+        if len > 63 {
+            return None;
+        }
+        labels.push(std::str::from_utf8(data.get(i..i + len)?).ok()?);
+        i += len;
+    }
+    let fixed = data.get(i..i + 4)?;
+    let qtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+    let qclass = u16::from_be_bytes([fixed[2], fixed[3]]);
+    Some((
+        labels.join(".").to_ascii_lowercase(),
+        qtype,
+        qclass,
+        &data[..i + 4],
+    ))
+}
+
+fn reply(header: &[u8], question: &[u8], rcode: u8, ancount: u16, answers: &[u8]) -> Vec<u8> {
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    // QR and AA set, opcode and RD copied from the query
+    let flags = 0x8400 | (flags & 0x7900) | u16::from(rcode);
+    let qdcount = u16::from(!question.is_empty());
+
+    let mut out = Vec::with_capacity(12 + question.len() + answers.len());
+    out.extend_from_slice(&header[..2]);
+    out.extend_from_slice(&flags.to_be_bytes());
+    out.extend_from_slice(&qdcount.to_be_bytes());
+    out.extend_from_slice(&ancount.to_be_bytes());
+    out.extend_from_slice(&[0, 0, 0, 0]);
+    out.extend_from_slice(question);
+    out.extend_from_slice(answers);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TYPE_AAAA: u16 = 28;
+
+    fn config() -> Config {
+        Config {
+            zone: "local".to_owned(),
+            answer: Ipv4Addr::new(10, 0, 0, 7),
+            ttl: 30,
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            servfail_percent: 0.0,
+            drop_percent: 0.0,
+        }
+    }
+
+    /// A query with RD set for `name`.
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            query.push(label.len() as u8);
+            query.extend_from_slice(label.as_bytes());
+        }
+        query.push(0);
+        query.extend_from_slice(&qtype.to_be_bytes());
+        query.extend_from_slice(&CLASS_IN.to_be_bytes());
+        query
+    }
+
+    /// Returns the response code and answer count.
+    fn status(response: &[u8]) -> (u8, u16) {
+        assert_eq!(response[..2], [0x12, 0x34], "id is copied");
+        // QR, AA and RD
+        assert_eq!(response[2] & 0x85, 0x85);
+        (
+            response[3] & 0xf,
+            u16::from_be_bytes([response[6], response[7]]),
+        )
+    }
+
+    #[test]
+    fn a_queries() {
+        let config = config();
+        let q = query("compute-0123.LOCAL", TYPE_A);
+        let response = respond(&config, &q, false).unwrap();
+        assert_eq!(status(&response), (0, 1));
+        // the question is echoed, followed by the record
+        assert_eq!(response[12..q.len()], q[12..]);
+        let record = &response[q.len()..];
+        assert_eq!(record[..4], [0xc0, 0x0c, 0, 1]);
+        assert_eq!(record[6..10], 30u32.to_be_bytes());
+        assert_eq!(record[10..], [0, 4, 10, 0, 0, 7]);
+
+        // the zone itself
+        let response = respond(&config, &query("local", TYPE_A), false).unwrap();
+        assert_eq!(status(&response), (0, 1));
+    }
+
+    #[test]
+    fn other_names_and_types() {
+        let config = config();
+        let response = respond(&config, &query("compute-1.local", TYPE_AAAA), false).unwrap();
+        assert_eq!(status(&response), (0, 0));
+        let mut chaos = query("compute-1.local", TYPE_A);
+        let len = chaos.len();
+        chaos[len - 1] = 3;
+        assert_eq!(status(&respond(&config, &chaos, false).unwrap()), (0, 0));
+
+        for name in ["example.com", "notlocal", "local.example"] {
+            let response = respond(&config, &query(name, TYPE_A), false).unwrap();
+            assert_eq!(status(&response), (RCODE_REFUSED, 0), "{name}");
+        }
+    }
+
+    #[test]
+    fn malformed_queries() {
+        let config = config();
+        let q = query("compute-1.local", TYPE_A);
+        // too short for a header, or a response
+        assert!(respond(&config, &q[..11], false).is_none());
+        let mut response = q.clone();
+        response[2] |= 0x80;
+        assert!(respond(&config, &response, false).is_none());
+
+        // truncated questions
+        for len in [12, 13, 20, q.len() - 1] {
+            let response = respond(&config, &q[..len], false).unwrap();
+            assert_eq!(status(&response), (RCODE_NOTIMP, 0), "{len}");
+            assert_eq!(response.len(), 12, "no question is echoed");
+        }
+
+        // a compression pointer in the question
+        let mut compressed = q[..12].to_vec();
+        compressed.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1]);
+        let response = respond(&config, &compressed, false).unwrap();
+        assert_eq!(status(&response), (RCODE_NOTIMP, 0));
+
+        // two questions, or another opcode
+        let mut two = q.clone();
+        two[5] = 2;
+        assert_eq!(
+            status(&respond(&config, &two, false).unwrap()).0,
+            RCODE_NOTIMP
+        );
+        let mut status_query = q.clone();
+        status_query[2] |= 2 << 3;
+        assert_eq!(
+            status(&respond(&config, &status_query, false).unwrap()).0,
+            RCODE_NOTIMP
+        );
+    }
+
+    #[test]
+    fn servfail() {
+        let config = config();
+        let q = query("compute-1.local", TYPE_A);
+        let response = respond(&config, &q, true).unwrap();
+        assert_eq!(status(&response), (RCODE_SERVFAIL, 0));
+        assert_eq!(response[12..], q[12..]);
+        // SERVFAIL comes before the zone check
+        let response = respond(&config, &query("example.com", TYPE_A), true).unwrap();
+        assert_eq!(status(&response), (RCODE_SERVFAIL, 0));
+    }
+
+    #[tokio::test]
+    async fn drop_and_answer() {
+        let server = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer = client.local_addr().unwrap();
+        let q = query("compute-1.local", TYPE_A);
+        let mut buf = [0; 512];
+
+        let dropping = Config {
+            drop_percent: 100.0,
+            ..config()
+        };
+        answer(server.clone(), Arc::new(dropping), q.clone(), peer).await;
+        let received = tokio::time::timeout(Duration::from_millis(100), client.recv(&mut buf));
+        assert!(received.await.is_err(), "dropped queries get no answer");
+
+        let failing = Config {
+            servfail_percent: 100.0,
+            ..config()
+        };
+        answer(server, Arc::new(failing), q, peer).await;
+        let n = client.recv(&mut buf).await.unwrap();
+        assert_eq!(status(&buf[..n]), (RCODE_SERVFAIL, 0));
+    }
+}
@@ -0,0 +1,34 @@
+//! Certificate loading shared by the mocks that serve TLS.
+
+use std::{fs::File, io::BufReader};
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls_pemfile::Item;
+
+/// Loads the certificate chain and key from PEM files, e.g. the ones `tls.sh` writes to
+/// `target/`. Panics if either is missing, since the mocks can't serve without them.
+pub fn load_pem(
+    cert_path: &str,
+    key_path: &str,
+) -> (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>) {
+    let mut certs = Vec::new();
+    let mut key = None;
+    for path in [cert_path, key_path] {
+        let file = File::open(path).unwrap_or_else(|e| panic!("could not open {path}: {e}"));
+        let mut reader = BufReader::new(file);
+        while let Some(item) = rustls_pemfile::read_one(&mut reader)
+            .unwrap_or_else(|e| panic!("invalid PEM file {path}: {e}"))
+        {
+            match item {
+                Item::X509Certificate(der) => certs.push(CertificateDer::from(der)),
+                Item::PKCS8Key(der) => key = Some(PrivateKeyDer::Pkcs8(der.into())),
+                Item::RSAKey(der) => key = Some(PrivateKeyDer::Pkcs1(der.into())),
+                Item::ECKey(der) => key = Some(PrivateKeyDer::Sec1(der.into())),
+                _ => {}
+            }
+        }
+    }
+    assert!(!certs.is_empty(), "no certificate in {cert_path}");
+    let key = key.unwrap_or_else(|| panic!("no private key in {key_path}"));
+    (certs, key)
+}
@@ -0,0 +1,182 @@
+//! Follows the links the proxy's console redirect auth backend sends to clients, like a user
+//! opening them in a browser.
+//!
+//! The proxy sends the link in a NoticeResponse and only finishes the startup once the session
+//! is approved. tokio-postgres holds startup notices back until then, so the link is read off the
+//! stream as it goes by instead.
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_postgres::tls::{ChannelBinding, TlsConnect, TlsStream};
+
+/// Longest NoticeResponse searched for a link, the proxy's greeting is much shorter.
+const MAX_NOTICE_LEN: usize = 8192;
+
+/// Wraps a TLS connector. Without an HTTP client the stream is passed through as is.
+pub struct FollowLinks<T> {
+    tls: T,
+    http: Option<reqwest::Client>,
+}
+
+impl<T> FollowLinks<T> {
+    pub fn new(tls: T, http: Option<reqwest::Client>) -> Self {
+        Self { tls, http }
+    }
+}
+
+impl<S, T> TlsConnect<S> for FollowLinks<T>
+where
+    T: TlsConnect<S>,
+    T::Future: Send + 'static,
+{
+    type Stream = LinkStream<T::Stream>;
+    type Error = T::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Stream, T::Error>> + Send>>;
+
+    fn connect(self, stream: S) -> Self::Future {
+        let connect = self.tls.connect(stream);
+        let http = self.http;
+        Box::pin(async move {
+            Ok(LinkStream {
+                inner: connect.await?,
+                follower: http.map(Follower::new),
+            })
+        })
+    }
+}
+
+pub struct LinkStream<S> {
+    inner: S,
+    /// Dropped once the startup is over.
+    follower: Option<Follower>,
+}
+
+/// Splits the bytes the server sends into messages, to find the link in the startup's notices.
+struct Follower {
+    http: reqwest::Client,
+    /// Tag and length of the current message.
+    header: Vec<u8>,
+    /// Body bytes of the current message still to come.
+    remaining: usize,
+    /// Body of the current message, if it's a notice.
+    notice: Option<Vec<u8>>,
+}
+
+impl Follower {
+    fn new(http: reqwest::Client) -> Self {
+        Self {
+            http,
+            header: Vec::with_capacity(5),
+            remaining: 0,
+            notice: None,
+        }
+    }
+
+    /// Returns whether to keep looking, which is until the first ReadyForQuery.
+    fn feed(&mut self, mut data: &[u8]) -> bool {
+        while !data.is_empty() {
+            if self.header.len() < 5 {
+                let n = (5 - self.header.len()).min(data.len());
+                self.header.extend_from_slice(&data[..n]);
+                data = &data[n..];
+                if self.header.len() < 5 {
+                    break;
+                }
+                let tag = self.header[0];
+                let len = u32::from_be_bytes(self.header[1..5].try_into().unwrap()) as usize;
+                if tag == b'Z' {
+                    return false;
+                }
+                self.remaining = len.saturating_sub(4);
+                self.notice = (tag == b'N' && self.remaining <= MAX_NOTICE_LEN)
+                    .then(|| Vec::with_capacity(self.remaining));
+            }
+
+            let n = self.remaining.min(data.len());
+            if let Some(notice) = &mut self.notice {
+                notice.extend_from_slice(&data[..n]);
+            }
+            self.remaining -= n;
+            data = &data[n..];
+            if self.remaining == 0 {
+                if let Some(notice) = self.notice.take() {
+                    self.follow(&notice);
+                }
+                self.header.clear();
+            }
+        }
+        true
+    }
+
+    fn follow(&self, notice: &[u8]) {
+        // fields are a type byte and a C string, 'M' is the message
+        let Some(message) = notice
+            .split(|&b| b == 0)
+            .find_map(|field| field.strip_prefix(b"M"))
+        else {
+            return;
+        };
+        let message = String::from_utf8_lossy(message);
+        let Some(link) = message
+            .split_whitespace()
+            .find(|word| word.starts_with("http://") || word.starts_with("https://"))
+        else {
+            return;
+        };
+
+        let request = self.http.get(link).send();
+        let link = link.to_owned();
+        tokio::spawn(async move {
+            if let Err(e) = request.await.and_then(|r| r.error_for_status()) {
+                println!("Could not open console redirect link {link}: {e}");
+            }
+        });
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for LinkStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Some(follower) = &mut self.follower {
+            if !follower.feed(&buf.filled()[filled..]) {
+                self.follower = None;
+            }
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for LinkStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl<S: TlsStream + Unpin> TlsStream for LinkStream<S> {
+    fn channel_binding(&self) -> ChannelBinding {
+        self.inner.channel_binding()
+    }
+}
@@ -11,6 +11,10 @@ use tokio_postgres::{tls::MakeTlsConnect, Config};
 use tokio_postgres_rustls::MakeRustlsConnect;
 use tokio_util::task::TaskTracker;
 
+use crate::console_redirect::FollowLinks;
+
+mod console_redirect;
+
 #[tokio::main]
 async fn main() {
     let host = std::env::var("PG_HOST").expect("missing var PG_HOST");
@@ -27,6 +31,27 @@ async fn main() {
         .expect("missing var PG_CONNECTION_MAX")
         .parse()
         .unwrap();
+    let user = std::env::var("PG_USER").unwrap_or_else(|_| "demo".to_owned());
+    let password = std::env::var("PG_PASSWORD").unwrap_or_else(|_| "password".to_owned());
+    // share of connections that name the endpoint in the password instead of the SNI
+    let password_hack_percent: f64 = std::env::var("PG_PASSWORD_HACK_PERCENT")
+        .map_or(0.0, |percent| percent.parse().unwrap());
+    assert!(
+        (0.0..=100.0).contains(&password_hack_percent),
+        "PG_PASSWORD_HACK_PERCENT {password_hack_percent} is not within 0..=100"
+    );
+    // open the console redirect links the proxy sends, which approves the sessions
+    let follow_console_links = std::env::var("PG_FOLLOW_CONSOLE_LINKS").is_ok_and(|follow| {
+        follow
+            .parse()
+            .expect("PG_FOLLOW_CONSOLE_LINKS must be true or false")
+    });
+    let http = follow_console_links.then(|| {
+        reqwest::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap()
+    });
 
     let report_interval = Duration::from_secs_f64(5.0);
     let interval = Duration::from_secs_f64(connection_rate.recip());
@@ -75,12 +100,21 @@ async fn main() {
         let connection_guard = conn_limiter.clone().acquire_owned().await.unwrap();
 
         let endpoint = thread_rng().sample(endpoint_dist);
-        let domain = format!("ep-hello-world-{endpoint}.{host}");
-        let dsn = format!("postgresql://demo:password@{domain}/db");
-        let config: Config = dsn.parse().unwrap();
+        let endpoint = format!("ep-hello-world-{endpoint}");
+        let mut config = Config::new();
+        config.user(&user).dbname("db");
+        let domain = if thread_rng().gen_bool(password_hack_percent / 100.0) {
+            config.password(format!("endpoint={endpoint};{password}"));
+            // an IP address doesn't go into the SNI, so the proxy can't see the endpoint there
+            "127.0.0.1".to_owned()
+        } else {
+            config.password(&password);
+            format!("{endpoint}.{host}")
+        };
         let tls =
             <MakeRustlsConnect as MakeTlsConnect<TcpStream>>::make_tls_connect(&mut tls, &domain)
                 .unwrap();
+        let tls = FollowLinks::new(tls, http.clone());
 
         let connect = TcpStream::connect(addr.clone());
 
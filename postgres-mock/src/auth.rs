@@ -0,0 +1,532 @@
+//! Authentication methods, picked per database and role like `pg_hba.conf`.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::{Arc, RwLock},
+};
+
+use bytes::BytesMut;
+use md5::{Digest, Md5};
+use rand::{thread_rng, RngCore};
+
+use crate::{
+    error_response, protocol_violation, read_packet,
+    scram::{random_salt, Exchange, Secret, DEFAULT_ITERATIONS, SCRAM_SHA_256, SCRAM_SHA_256_PLUS},
+    write_message, Stream,
+};
+
+const DEFAULT_PASSWORD: &str = "password";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Trust,
+    Reject,
+    /// Cleartext password.
+    Password,
+    Md5,
+    ScramSha256,
+}
+
+struct HbaRule {
+    /// Database name or `all`.
+    database: String,
+    /// Role name or `all`.
+    user: String,
+    method: Method,
+}
+
+/// Rules from `$POSTGRES_MOCK_HBA`, `database user method` separated by `;` or newlines, like
+/// `pg_hba.conf` without the connection type and address. The first matching rule applies.
+/// Without it every connection uses `scram-sha-256`.
+pub struct Hba {
+    rules: Vec<HbaRule>,
+}
+
+impl Hba {
+    pub fn from_env() -> Self {
+        let Ok(rules) = std::env::var("POSTGRES_MOCK_HBA") else {
+            return Self {
+                rules: vec![HbaRule {
+                    database: "all".to_owned(),
+                    user: "all".to_owned(),
+                    method: Method::ScramSha256,
+                }],
+            };
+        };
+        let rules: Vec<_> = rules
+            .split([';', '\n'])
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                let fields: Vec<_> = line.split_whitespace().collect();
+                let [database, user, method] = fields[..] else {
+                    panic!("POSTGRES_MOCK_HBA rule {line} is not `database user method`");
+                };
+                let method = match method {
+                    "trust" => Method::Trust,
+                    "reject" => Method::Reject,
+                    "password" => Method::Password,
+                    "md5" => Method::Md5,
+                    "scram-sha-256" => Method::ScramSha256,
+                    _ => panic!("unsupported auth method {method} in POSTGRES_MOCK_HBA"),
+                };
+                HbaRule {
+                    database: database.to_owned(),
+                    user: user.to_owned(),
+                    method,
+                }
+            })
+            .collect();
+        println!("Loaded {} hba rules", rules.len());
+        Self { rules }
+    }
+
+    fn method(&self, database: &str, user: &str) -> Option<Method> {
+        self.rules
+            .iter()
+            .find(|rule| {
+                (rule.database == "all" || rule.database == database)
+                    && (rule.user == "all" || rule.user == user)
+            })
+            .map(|rule| rule.method)
+    }
+}
+
+/// What the server stores for a role. Like Postgres, a role has a SCRAM or an MD5 secret,
+/// but roles configured with a password can use both.
+pub struct Role {
+    password: Option<String>,
+    scram: Option<Secret>,
+    md5: Option<String>,
+}
+
+impl Role {
+    fn from_password(password: &str, scram: &ScramParams) -> Self {
+        Self {
+            password: Some(password.to_owned()),
+            scram: Some(Secret::from_password(
+                password,
+                scram.iterations,
+                &scram.salt,
+            )),
+            md5: None,
+        }
+    }
+
+    /// Parses a secret like Postgres stores it, or derives one from a password.
+    fn from_secret(secret: &str, scram: &ScramParams) -> Option<Self> {
+        let role = if secret.starts_with("SCRAM-SHA-256$") {
+            Role {
+                password: None,
+                scram: Some(Secret::parse(secret)?),
+                md5: None,
+            }
+        } else if is_md5_secret(secret) {
+            Role {
+                password: None,
+                scram: None,
+                md5: Some(secret.to_owned()),
+            }
+        } else {
+            Role::from_password(secret, scram)
+        };
+        Some(role)
+    }
+
+    /// The `md5<hex>` secret, salted with the role name.
+    fn md5(&self, user: &str) -> Option<String> {
+        self.md5.clone().or_else(|| {
+            self.password
+                .as_deref()
+                .map(|password| format!("md5{}", md5_hex(&[password.as_bytes(), user.as_bytes()])))
+        })
+    }
+}
+
+/// How SCRAM secrets are derived from configured passwords. They only match cplane-mock's
+/// secrets if both use the same salt and iterations.
+struct ScramParams {
+    /// `$POSTGRES_MOCK_SCRAM_ITERATIONS`, 4096 by default like cplane-mock's.
+    iterations: u32,
+    /// `$POSTGRES_MOCK_SCRAM_SALT`, random by default like cplane-mock's.
+    salt: String,
+}
+
+impl ScramParams {
+    fn from_env() -> Self {
+        let iterations = match std::env::var("POSTGRES_MOCK_SCRAM_ITERATIONS") {
+            Ok(iterations) => iterations
+                .parse()
+                .ok()
+                .filter(|&n| n > 0)
+                .unwrap_or_else(|| panic!("invalid POSTGRES_MOCK_SCRAM_ITERATIONS {iterations}")),
+            Err(_) => DEFAULT_ITERATIONS,
+        };
+        let salt = match std::env::var("POSTGRES_MOCK_SCRAM_SALT") {
+            Ok(salt) => {
+                assert!(
+                    base64::decode(&salt).is_ok_and(|salt| !salt.is_empty()),
+                    "invalid POSTGRES_MOCK_SCRAM_SALT {salt}"
+                );
+                salt
+            }
+            Err(_) => {
+                println!(
+                    "POSTGRES_MOCK_SCRAM_SALT is unset, so password secrets won't match cplane-mock's"
+                );
+                random_salt()
+            }
+        };
+        Self { iterations, salt }
+    }
+}
+
+/// Role secrets from `$POSTGRES_MOCK_ROLES`, comma separated `role=password`,
+/// `role=SCRAM-SHA-256$...` or `role=md5<hex>` pairs. Without it every role has the password
+/// `password`. `ALTER ROLE` replaces them while running.
+pub struct Roles {
+    scram: ScramParams,
+    default: Option<Arc<Role>>,
+    roles: RwLock<HashMap<String, Arc<Role>>>,
+}
+
+impl Roles {
+    pub fn from_env() -> Self {
+        let scram = ScramParams::from_env();
+        let Ok(roles) = std::env::var("POSTGRES_MOCK_ROLES") else {
+            return Self {
+                default: Some(Arc::new(Role::from_password(DEFAULT_PASSWORD, &scram))),
+                roles: RwLock::new(HashMap::new()),
+                scram,
+            };
+        };
+        let roles: HashMap<_, _> = roles
+            .split(',')
+            .filter(|pair| !pair.trim().is_empty())
+            .map(|pair| {
+                let (role, secret) = pair.trim().split_once('=').unwrap_or_else(|| {
+                    panic!("POSTGRES_MOCK_ROLES entry {pair} is not role=secret")
+                });
+                let secret = Role::from_secret(secret, &scram)
+                    .unwrap_or_else(|| panic!("invalid SCRAM secret for role {role}"));
+                (role.to_owned(), Arc::new(secret))
+            })
+            .collect();
+        println!("Loaded secrets for {} roles", roles.len());
+        Self {
+            scram,
+            default: None,
+            roles: RwLock::new(roles),
+        }
+    }
+
+    fn get(&self, role: &str) -> Option<Arc<Role>> {
+        let roles = self.roles.read().unwrap();
+        roles.get(role).or(self.default.as_ref()).cloned()
+    }
+
+    /// Replaces the role's secret, adding the role if needed. Without a secret the role can't
+    /// log in with a password, like after `ALTER ROLE ... PASSWORD NULL`.
+    pub fn set_password(&self, role: &str, secret: Option<&str>) -> Result<(), String> {
+        let secret = match secret {
+            Some(secret) => Role::from_secret(secret, &self.scram)
+                .ok_or_else(|| format!("invalid SCRAM secret for role {role}"))?,
+            None => Role {
+                password: None,
+                scram: None,
+                md5: None,
+            },
+        };
+        self.roles
+            .write()
+            .unwrap()
+            .insert(role.to_owned(), Arc::new(secret));
+        Ok(())
+    }
+}
+
+/// Whether the query is an `ALTER ROLE`, which [`parse_alter_role`] should handle.
+pub fn is_alter_role(query: &str) -> bool {
+    strip_keyword(query.trim_start(), "alter").is_some_and(|rest| {
+        strip_keyword(rest, "role").is_some() || strip_keyword(rest, "user").is_some()
+    })
+}
+
+/// Parses `ALTER ROLE name [WITH] PASSWORD 'secret'` or `... PASSWORD NULL` into the role and
+/// its new secret. The role name is folded to lower case unless it is quoted, like in Postgres.
+pub fn parse_alter_role(query: &str) -> Option<(String, Option<String>)> {
+    let query = query.trim().trim_end_matches(';').trim_end();
+    let rest = strip_keyword(query, "alter")?;
+    let rest = strip_keyword(rest, "role").or_else(|| strip_keyword(rest, "user"))?;
+    let (role, rest) = identifier(rest)?;
+    let rest = strip_keyword(rest, "with").unwrap_or(rest);
+    let rest = strip_keyword(rest, "password")?;
+    if let Some(rest) = strip_keyword(rest, "null") {
+        return rest.is_empty().then_some((role, None));
+    }
+    let (secret, rest) = quoted(rest, '\'')?;
+    rest.trim_start().is_empty().then_some((role, Some(secret)))
+}
+
+/// Strips a case insensitive keyword and the whitespace after it.
+fn strip_keyword<'a>(s: &'a str, keyword: &str) -> Option<&'a str> {
+    let rest = s
+        .get(..keyword.len())
+        .filter(|word| word.eq_ignore_ascii_case(keyword))
+        .map(|_| &s[keyword.len()..])?;
+    if rest.starts_with(is_identifier_char) {
+        return None;
+    }
+    Some(rest.trim_start())
+}
+
+fn identifier(s: &str) -> Option<(String, &str)> {
+    if s.starts_with('"') {
+        let (name, rest) = quoted(s, '"')?;
+        return (!name.is_empty()).then(|| (name, rest.trim_start()));
+    }
+    let end = s.find(|c| !is_identifier_char(c)).unwrap_or(s.len());
+    if end == 0 {
+        return None;
+    }
+    Some((s[..end].to_lowercase(), s[end..].trim_start()))
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$'
+}
+
+/// Parses a string quoted with `quote`, where a doubled quote stands for itself.
+fn quoted(s: &str, quote: char) -> Option<(String, &str)> {
+    let mut rest = s.strip_prefix(quote)?;
+    let mut value = String::new();
+    loop {
+        let end = rest.find(quote)?;
+        value.push_str(&rest[..end]);
+        rest = &rest[end + 1..];
+        match rest.strip_prefix(quote) {
+            Some(after) => {
+                value.push(quote);
+                rest = after;
+            }
+            None => return Some((value, rest)),
+        }
+    }
+}
+
+fn is_md5_secret(secret: &str) -> bool {
+    secret
+        .strip_prefix("md5")
+        .is_some_and(|hex| hex.len() == 32 && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+/// Runs the method the hba rules pick for the connection, up to but excluding AuthenticationOk.
+///
+/// `channel_binding` is the `tls-server-end-point` data of the connection, if it has TLS.
+pub async fn authenticate(
+    s: &mut dyn Stream,
+    buf: &mut BytesMut,
+    hba: &Hba,
+    roles: &Roles,
+    database: &str,
+    user: &str,
+    channel_binding: Option<&[u8]>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let Some(method) = hba.method(database, user) else {
+        let message = format!("no pg_hba.conf entry for user \"{user}\", database \"{database}\"");
+        error_response(s, "28000", &message).await?;
+        return Err(message.into());
+    };
+    let role = roles.get(user);
+    let role = role.as_deref();
+
+    let authenticated = match method {
+        Method::Trust => true,
+        Method::Reject => {
+            let message = format!(
+                "pg_hba.conf rejects connection for user \"{user}\", database \"{database}\""
+            );
+            error_response(s, "28000", &message).await?;
+            return Err(message.into());
+        }
+        Method::Password => {
+            // AuthenticationCleartextPassword
+            write_message(s, b'R', &[&3u32.to_be_bytes()]).await?;
+            let Some(password) = read_password(s, buf).await? else {
+                return protocol_violation(s, "expected password response").await;
+            };
+            role.is_some_and(|role| match (&role.scram, role.md5(user)) {
+                (Some(secret), _) => secret.verify_password(&password),
+                (None, Some(md5)) => {
+                    md5 == format!("md5{}", md5_hex(&[password.as_bytes(), user.as_bytes()]))
+                }
+                (None, None) => false,
+            })
+        }
+        // like Postgres, md5 rules use SCRAM for roles that only have a SCRAM secret
+        Method::Md5 if role.is_none_or(|role| role.md5(user).is_some()) => {
+            let mut salt = [0; 4];
+            thread_rng().fill_bytes(&mut salt);
+            // AuthenticationMD5Password
+            write_message(s, b'R', &[&5u32.to_be_bytes(), &salt]).await?;
+            let Some(response) = read_password(s, buf).await? else {
+                return protocol_violation(s, "expected password response").await;
+            };
+            role.and_then(|role| role.md5(user)).is_some_and(|md5| {
+                response == format!("md5{}", md5_hex(&[&md5.as_bytes()[3..], &salt]))
+            })
+        }
+        Method::Md5 | Method::ScramSha256 => {
+            let secret = role.and_then(|role| role.scram.as_ref());
+            match scram(s, buf, secret, channel_binding).await? {
+                Some(authenticated) => authenticated,
+                // the exchange already failed with a protocol violation
+                None => return Err("SCRAM exchange failed".into()),
+            }
+        }
+    };
+
+    if !authenticated {
+        let message = format!("password authentication failed for user \"{user}\"");
+        error_response(s, "28P01", &message).await?;
+        return Err(message.into());
+    }
+    Ok(())
+}
+
+/// Runs a SCRAM exchange. Returns whether the client proved it knows the password, or `None`
+/// if the exchange failed with a protocol violation.
+async fn scram(
+    s: &mut dyn Stream,
+    buf: &mut BytesMut,
+    secret: Option<&Secret>,
+    channel_binding: Option<&[u8]>,
+) -> Result<Option<bool>, Box<dyn Error + Send + Sync>> {
+    // channel binding is only possible over TLS
+    let mut mechanisms = Vec::new();
+    if channel_binding.is_some() {
+        mechanisms.extend_from_slice(SCRAM_SHA_256_PLUS.as_bytes());
+        mechanisms.push(0);
+    }
+    mechanisms.extend_from_slice(SCRAM_SHA_256.as_bytes());
+    mechanisms.extend_from_slice(b"\0\0");
+    // AuthenticationSASL
+    write_message(s, b'R', &[&10u32.to_be_bytes(), &mechanisms]).await?;
+
+    let initial = read_packet(s, buf, 1).await?;
+    let Some((mechanism, client_first)) = parse_sasl_initial_response(&initial) else {
+        return sasl_violation(s, "expected SASLInitialResponse").await;
+    };
+    let exchange = match Exchange::start(secret, mechanism, client_first, channel_binding) {
+        Ok(exchange) => exchange,
+        Err(e) => return sasl_violation(s, &e).await,
+    };
+
+    // AuthenticationSASLContinue
+    write_message(
+        s,
+        b'R',
+        &[&11u32.to_be_bytes(), exchange.server_first().as_bytes()],
+    )
+    .await?;
+
+    // SASLResponse
+    let response = read_packet(s, buf, 1).await?;
+    let client_final = match response.split_first() {
+        Some((b'p', rest)) => std::str::from_utf8(&rest[4..]).ok(),
+        _ => None,
+    };
+    let Some(client_final) = client_final else {
+        return sasl_violation(s, "expected SASLResponse").await;
+    };
+    let server_final = match exchange.finish(client_final) {
+        Ok(Some(server_final)) => server_final,
+        Ok(None) => return Ok(Some(false)),
+        Err(e) => return sasl_violation(s, &e).await,
+    };
+
+    // AuthenticationSASLFinal
+    write_message(s, b'R', &[&12u32.to_be_bytes(), server_final.as_bytes()]).await?;
+    Ok(Some(true))
+}
+
+async fn sasl_violation(
+    s: &mut dyn Stream,
+    message: &str,
+) -> Result<Option<bool>, Box<dyn Error + Send + Sync>> {
+    error_response(s, "08P01", message).await?;
+    Ok(None)
+}
+
+/// Returns the mechanism and the client-first-message of a SASLInitialResponse.
+fn parse_sasl_initial_response(message: &[u8]) -> Option<(&str, &str)> {
+    let body = message.strip_prefix(b"p")?.get(4..)?;
+    let end = body.iter().position(|&b| b == 0)?;
+    let mechanism = std::str::from_utf8(&body[..end]).ok()?;
+    let data = body.get(end + 1..)?;
+    let len = i32::from_be_bytes(data.get(..4)?.try_into().unwrap());
+    let data = &data[4..];
+    if usize::try_from(len).ok()? != data.len() {
+        return None;
+    }
+    Some((mechanism, std::str::from_utf8(data).ok()?))
+}
+
+/// Reads a PasswordMessage, a C string.
+async fn read_password(
+    s: &mut dyn Stream,
+    buf: &mut BytesMut,
+) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+    let message = read_packet(s, buf, 1).await?;
+    let password = match message.split_first() {
+        Some((b'p', rest)) => rest[4..]
+            .strip_suffix(b"\0")
+            .and_then(|password| std::str::from_utf8(password).ok()),
+        _ => None,
+    };
+    Ok(password.map(str::to_owned))
+}
+
+fn md5_hex(parts: &[&[u8]]) -> String {
+    let mut md5 = Md5::new();
+    for part in parts {
+        md5.update(part);
+    }
+    md5.finalize().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alter_role() {
+        let secret = "SCRAM-SHA-256$4096:c2FsdA==$a2V5:a2V5";
+        assert_eq!(
+            parse_alter_role(&format!("ALTER ROLE \"Demo\" WITH PASSWORD '{secret}';")),
+            Some(("Demo".to_owned(), Some(secret.to_owned())))
+        );
+        assert_eq!(
+            parse_alter_role("alter user Reader password 'it''s'"),
+            Some(("reader".to_owned(), Some("it's".to_owned())))
+        );
+        assert_eq!(
+            parse_alter_role("ALTER ROLE \"a\"\"b\" PASSWORD NULL"),
+            Some(("a\"b".to_owned(), None))
+        );
+
+        for query in [
+            "ALTER ROLE demo",
+            "ALTER ROLE demo PASSWORD 'x' VALID UNTIL 'infinity'",
+            "ALTER ROLE demo PASSWORD 'x",
+            "ALTER ROLE \"\" PASSWORD 'x'",
+            "ALTER ROLE demo RENAME TO other",
+        ] {
+            assert!(is_alter_role(query), "{query}");
+            assert_eq!(parse_alter_role(query), None, "{query}");
+        }
+        assert!(!is_alter_role("ALTER ROLEdemo PASSWORD 'x'"));
+        assert!(!is_alter_role("ALTER TABLE t ADD c int"));
+        assert!(!is_alter_role("select 1;"));
+    }
+}
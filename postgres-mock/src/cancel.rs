@@ -0,0 +1,94 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use rand::{thread_rng, Rng};
+use tokio::sync::Notify;
+
+/// Maps the (pid, secret) pairs sent in BackendKeyData to sessions, for CancelRequest.
+#[derive(Default)]
+pub struct Registry {
+    next_pid: AtomicU32,
+    /// Secret and cancel signal by pid.
+    sessions: Mutex<HashMap<i32, (i32, Arc<Notify>)>>,
+}
+
+/// A registered session, unregistered on drop.
+pub struct Session {
+    registry: Arc<Registry>,
+    pub pid: i32,
+    pub secret: i32,
+    /// Wakes the session's running query, if any. Idle sessions ignore it, like in Postgres.
+    pub cancel: Arc<Notify>,
+}
+
+impl Registry {
+    pub fn register(self: &Arc<Self>) -> Session {
+        let secret = thread_rng().gen();
+        let cancel = Arc::new(Notify::new());
+        let mut sessions = self.sessions.lock().unwrap();
+        // pids are in 1..=i32::MAX like in Postgres. After wrapping around, pids of sessions that
+        // are still open are skipped.
+        let pid = loop {
+            let pid = (self.next_pid.fetch_add(1, Ordering::Relaxed) & i32::MAX as u32) as i32;
+            if pid != 0 && !sessions.contains_key(&pid) {
+                break pid;
+            }
+        };
+        sessions.insert(pid, (secret, cancel.clone()));
+        drop(sessions);
+        Session {
+            registry: self.clone(),
+            pid,
+            secret,
+            cancel,
+        }
+    }
+
+    /// Cancels the session's running query. Unknown keys are ignored, and the client isn't told.
+    pub fn cancel(&self, pid: i32, secret: i32) {
+        match self.sessions.lock().unwrap().get(&pid) {
+            Some((s, cancel)) if *s == secret => {
+                println!("Cancelling the query of session {pid}");
+                cancel.notify_waiters();
+            }
+            _ => println!("CancelRequest with unknown key for pid {pid}"),
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.registry.sessions.lock().unwrap().remove(&self.pid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pids_skip_zero_and_open_sessions() {
+        let registry = Arc::new(Registry::default());
+        let first = registry.register();
+        let second = registry.register();
+        assert_eq!((first.pid, second.pid), (1, 2));
+
+        // about to wrap around, with pid 1 still open
+        registry.next_pid.store(i32::MAX as u32, Ordering::Relaxed);
+        drop(second);
+        let last = registry.register();
+        assert_eq!(last.pid, i32::MAX);
+        let wrapped = registry.register();
+        assert_eq!(wrapped.pid, 2);
+        assert_eq!(registry.sessions.lock().unwrap().len(), 3);
+
+        // the u32 counter wraps to 0 as well
+        registry.next_pid.store(u32::MAX, Ordering::Relaxed);
+        assert_eq!(registry.register().pid, 3);
+    }
+}
@@ -1,45 +1,101 @@
-use std::{error::Error, time::Duration};
+use std::{error::Error, sync::Arc, time::Duration};
 
+use auth::{Hba, Roles};
 use bytes::{Buf, Bytes, BytesMut};
-use hmac::{Hmac, Mac};
+use cancel::Registry;
+use tls::{Tls, ALPN_POSTGRESQL};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::{TcpListener, TcpStream},
     select,
     signal::unix::{signal, SignalKind},
+    sync::Notify,
 };
 
+mod auth;
+mod cancel;
+mod scram;
+mod tls;
+
+const CANCEL_REQUEST_CODE: i32 = 80877102;
+const SSL_REQUEST_CODE: u32 = 80877103;
+const GSSENC_REQUEST_CODE: u32 = 80877104;
+
+/// A client connection, with or without TLS.
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
 #[tokio::main]
 async fn main() {
     let mut signal = signal(SignalKind::terminate()).unwrap();
+    let hba = Arc::new(Hba::from_env());
+    let roles = Arc::new(Roles::from_env());
+    let tls = Tls::from_env().map(Arc::new);
+    let registry = Arc::new(Registry::default());
     let listener = TcpListener::bind("0.0.0.0:5432").await.unwrap();
     loop {
         select! {
-            s = listener.accept() => tokio::spawn(handle(s.unwrap().0)),
+            s = listener.accept() => {
+                let (tls, hba, roles) = (tls.clone(), hba.clone(), roles.clone());
+                tokio::spawn(handle(s.unwrap().0, tls, hba, roles, registry.clone()))
+            }
             _ = signal.recv() => break,
         };
     }
 }
 
-async fn handle(mut s: TcpStream) -> Result<(), Box<dyn Error + Send + Sync>> {
+async fn handle(
+    s: TcpStream,
+    tls: Option<Arc<Tls>>,
+    hba: Arc<Hba>,
+    roles: Arc<Roles>,
+    registry: Arc<Registry>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
     let mut buf = BytesMut::new();
-    handshake(&mut s, &mut buf).await?;
+    let (mut s, startup, channel_binding) = negotiate(s, &mut buf, tls.as_deref()).await?;
+    // CancelRequest: length, code, pid and secret, with no response
+    if let [16, CANCEL_REQUEST_CODE, pid, secret] = read_i32s(&startup)[..] {
+        registry.cancel(pid, secret);
+        return Ok(());
+    }
+    handshake(&mut *s, &mut buf, startup, &hba, &roles, channel_binding).await?;
+
+    let session = registry.register();
+    // BackendKeyData
+    write_message(
+        &mut *s,
+        b'K',
+        &[&session.pid.to_be_bytes(), &session.secret.to_be_bytes()],
+    )
+    .await?;
 
     loop {
         // Ready for query (idle)
         s.write_all(&b"Z\x00\x00\x00\x05I"[..]).await?;
-        let query = read_packet(&mut s, &mut buf, 1).await?;
+        let query = read_packet(&mut *s, &mut buf, 1).await?;
 
         match query[0] {
             b'X' => break Ok(()),
-            b'Q' => simple_query(&mut s, query).await?,
-            b'P' => extended_query(&mut s, &mut buf, query).await?,
+            b'Q' => simple_query(&mut *s, query, &session.cancel, &roles).await?,
+            b'P' => extended_query(&mut *s, &mut buf, query, &session.cancel).await?,
             x => unimplemented!("unknown command code {x}"),
         }
     }
 }
 
-async fn simple_query(s: &mut TcpStream, query: Bytes) -> Result<(), Box<dyn Error + Send + Sync>> {
+async fn simple_query(
+    s: &mut dyn Stream,
+    query: Bytes,
+    cancel: &Notify,
+    roles: &Roles,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let text = String::from_utf8_lossy(&query[5..]);
+    let text = text.trim_end_matches('\0');
+    if auth::is_alter_role(text) {
+        return alter_role(s, text, roles).await;
+    }
+
     match &query[5..] {
         b"select 1;\0" => {
             // row description: ?column?: int4
@@ -51,9 +107,12 @@ async fn simple_query(s: &mut TcpStream, query: Bytes) -> Result<(), Box<dyn Err
             s.write_all(&b"C\x00\x00\x00\x0dSELECT 1\0"[..]).await?;
         }
         b"select pg_sleep(5);\0" => {
-            tokio::time::sleep(Duration::from_secs(5)).await;
-            // empty response
-            s.write_all(&b"I\x00\x00\x00\x04"[..]).await?;
+            if sleep(Duration::from_secs(5), cancel).await {
+                // empty response
+                s.write_all(&b"I\x00\x00\x00\x04"[..]).await?;
+            } else {
+                query_canceled(s).await?;
+            }
         }
         _ => {
             // empty response
@@ -64,9 +123,10 @@ async fn simple_query(s: &mut TcpStream, query: Bytes) -> Result<(), Box<dyn Err
 }
 
 async fn extended_query(
-    s: &mut TcpStream,
+    s: &mut dyn Stream,
     buf: &mut BytesMut,
     mut query: Bytes,
+    cancel: &Notify,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     assert_eq!(query[5], 0, "unexpected named parse command");
     query.advance(6);
@@ -92,7 +152,7 @@ async fn extended_query(
         b"select 1\0" => {
             s.write_all(&b"T\x00\x00\x00\x21\x00\x01?column?\0\x00\x00\x00\x00\x00\x00\x00\x00\x00\x17\x00\x04\x00\x00\x00\x00\x00\x00"[..]).await?;
         }
-        b"select pg_sleep(5)\0" | _ => {
+        _ => {
             // If no result columns, send NoData
             s.write_all(&b"n\x00\x00\x00\x04"[..]).await?;
         }
@@ -135,9 +195,12 @@ async fn extended_query(
             s.write_all(&b"C\x00\x00\x00\x0dSELECT 1\0"[..]).await?;
         }
         b"select pg_sleep(5)\0" => {
-            tokio::time::sleep(Duration::from_secs(5)).await;
-            // complete: SELECT 1 (or NoData if no output)
-            s.write_all(&b"C\x00\x00\x00\x0dSELECT 1\0"[..]).await?;
+            if sleep(Duration::from_secs(5), cancel).await {
+                // complete: SELECT 1 (or NoData if no output)
+                s.write_all(&b"C\x00\x00\x00\x0dSELECT 1\0"[..]).await?;
+            } else {
+                query_canceled(s).await?;
+            }
         }
         _ => {
             // complete SELECT 1?
@@ -149,84 +212,184 @@ async fn extended_query(
     Ok(())
 }
 
+/// Runs `ALTER ROLE name [WITH] PASSWORD ...`, which is how cplane-mock pushes role secrets.
+async fn alter_role(
+    s: &mut dyn Stream,
+    query: &str,
+    roles: &Roles,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let Some((role, secret)) = auth::parse_alter_role(query) else {
+        let message = format!("only ALTER ROLE name PASSWORD '...' is supported, not {query}");
+        return query_error(s, "42601", &message).await;
+    };
+    if let Err(e) = roles.set_password(&role, secret.as_deref()) {
+        return query_error(s, "22023", &e).await;
+    }
+    println!("Set password of role {role}");
+    write_message(s, b'C', &[b"ALTER ROLE\0"]).await
+}
+
+/// Answers SSLRequest and GSSENCRequest until the StartupMessage, which it returns with the
+/// connection, upgraded to TLS if the client asked for it, and its channel binding data.
+async fn negotiate<'t>(
+    mut s: TcpStream,
+    buf: &mut BytesMut,
+    tls: Option<&'t Tls>,
+) -> Result<(Box<dyn Stream>, Bytes, Option<&'t [u8]>), Box<dyn Error + Send + Sync>> {
+    // direct TLS starts with the TLS handshake instead of an SSLRequest
+    let mut first = [0; 1];
+    s.peek(&mut first).await?;
+    if first[0] == 0x16 {
+        let tls = tls.ok_or("direct TLS connection, but TLS isn't configured")?;
+        let stream = tls.acceptor.accept(s).await?;
+        if stream.get_ref().1.alpn_protocol() != Some(ALPN_POSTGRESQL) {
+            return Err("direct TLS connection without ALPN postgresql".into());
+        }
+        let mut s: Box<dyn Stream> = Box::new(stream);
+        let startup = read_packet(&mut *s, buf, 0).await?;
+        return Ok((s, startup, Some(&tls.server_end_point)));
+    }
+
+    loop {
+        let packet = read_packet(&mut s, buf, 0).await?;
+        let code = match packet.get(4..8) {
+            Some(code) if packet.len() == 8 => u32::from_be_bytes(code.try_into().unwrap()),
+            _ => return Ok((Box::new(s), packet, None)),
+        };
+        match (code, tls) {
+            (SSL_REQUEST_CODE, Some(tls)) => {
+                // anything sent before the TLS handshake could be injected by a MITM
+                if !buf.is_empty() {
+                    return Err("received unencrypted data after SSL request".into());
+                }
+                s.write_all(b"S").await?;
+                let mut s: Box<dyn Stream> = Box::new(tls.acceptor.accept(s).await?);
+                let startup = read_packet(&mut *s, buf, 0).await?;
+                return Ok((s, startup, Some(&tls.server_end_point)));
+            }
+            // the client may retry with SSLRequest, or go on with the StartupMessage
+            (SSL_REQUEST_CODE, None) | (GSSENC_REQUEST_CODE, _) => s.write_all(b"N").await?,
+            _ => return Ok((Box::new(s), packet, None)),
+        }
+    }
+}
+
 async fn handshake(
-    s: &mut TcpStream,
+    s: &mut dyn Stream,
     buf: &mut BytesMut,
+    startup: Bytes,
+    hba: &Hba,
+    roles: &Roles,
+    channel_binding: Option<&[u8]>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let _startup = read_packet(s, &mut *buf, 0).await?;
-
-    // we support only scram-sha-256 (since proxy will require it)
-    s.write_all(&b"R\x00\x00\x00\x17\x00\x00\x00\x0aSCRAM-SHA-256\0\0"[..])
-        .await
-        .unwrap();
-
-    // wait for client first message
-    let auth_resp = read_packet(s, &mut *buf, 1).await?;
-    let salt = auth_resp
-        .strip_prefix(&b"p\x00\x00\x00\x36SCRAM-SHA-256\0\x00\x00\x00\x20n,,n=,r="[..])
-        .unwrap();
-
-    // form server first message
-    let mut server_first_message = BytesMut::new();
-    server_first_message.extend_from_slice(&b"R\x00\x00\x00\x00\x00\x00\x00\x0br="[..]);
-    server_first_message.extend_from_slice(salt);
-    server_first_message.extend_from_slice(&[b'A'; 16]);
-    server_first_message.extend_from_slice(&b",s=M2ZX/kfDSd3vv5iFO/QNUA==,i=4096"[..]);
-    let len = ((server_first_message.len() - 1) as u32).to_be_bytes();
-    server_first_message[1..5].copy_from_slice(&len);
-
-    s.write_all(&server_first_message).await.unwrap();
-
-    // wait for client final message. we don't care for the data because who needs authentication...
-    let _auth_resp = read_packet(s, &mut *buf, 1).await?;
-
-    // server final message: proof for the client
-    let server_key = b"\xde\x73\x22\xf1\xe0\x52\x1e\x08\x08\x04\xd4\xa0\x02\x29\x3a\x95\x09\xc4\xde\x14\x1c\xb1\x2f\xa6\xcb\x29\x59\x95\x88\x0d\x03\x55";
-    let sig = Hmac::<sha2::Sha256>::new_from_slice(&server_key[..])
-        .unwrap()
-        .chain_update(b"n=,r=")
-        .chain_update(salt)
-        .chain_update(b",")
-        .chain_update(&server_first_message[9..])
-        .chain_update(b",")
-        .chain_update(b"c=biws,r=")
-        .chain_update(salt)
-        .chain_update(b"AAAAAAAAAAAAAAAA")
-        .finalize()
-        .into_bytes();
-
-    let mut sig64 = [0; 44];
-    base64::encode_config_slice(sig, base64::STANDARD, &mut sig64);
-
-    let mut server_final_message = BytesMut::new();
-    server_final_message.extend_from_slice(&b"R\x00\x00\x00\x00\x00\x00\x00\x0cv="[..]);
-    server_final_message.extend_from_slice(&sig64);
-    let len = ((server_final_message.len() - 1) as u32).to_be_bytes();
-    server_final_message[1..5].copy_from_slice(&len);
-
-    s.write_all(&server_final_message).await.unwrap();
+    // protocol 3.x, anything else is a request we don't support here
+    if startup.get(4..6) != Some(&[0, 3][..]) {
+        return protocol_violation(s, "unsupported frontend protocol").await;
+    }
+    // parameters are pairs of C strings after the length and protocol version
+    let mut params = startup.get(8..).unwrap_or_default().split(|&b| b == 0);
+    let (mut user, mut database) = (None, None);
+    while let (Some(key), Some(value)) = (params.next(), params.next()) {
+        match key {
+            b"user" => user = Some(String::from_utf8_lossy(value).into_owned()),
+            b"database" => database = Some(String::from_utf8_lossy(value).into_owned()),
+            _ => {}
+        }
+    }
+    let user = user.ok_or("startup message has no user")?;
+    let database = database.unwrap_or_else(|| user.clone());
+
+    auth::authenticate(s, buf, hba, roles, &database, &user, channel_binding).await?;
 
     // auth ok
     s.write_all(&b"R\x00\x00\x00\x08\x00\x00\x00\x00"[..])
-        .await
-        .unwrap();
+        .await?;
 
     Ok(())
 }
 
+async fn write_message(
+    s: &mut dyn Stream,
+    tag: u8,
+    parts: &[&[u8]],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let len: usize = parts.iter().map(|part| part.len()).sum();
+    let mut message = BytesMut::with_capacity(5 + len);
+    message.extend_from_slice(&[tag]);
+    message.extend_from_slice(&((len + 4) as u32).to_be_bytes());
+    for part in parts {
+        message.extend_from_slice(part);
+    }
+    s.write_all(&message).await?;
+    Ok(())
+}
+
+/// Fails the handshake with SQLSTATE 08P01, like Postgres does for malformed auth messages.
+async fn protocol_violation(
+    s: &mut dyn Stream,
+    message: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    error_response(s, "08P01", message).await?;
+    Err(message.into())
+}
+
+/// Sleeps like `pg_sleep`. Returns false if the query got cancelled instead.
+async fn sleep(duration: Duration, cancel: &Notify) -> bool {
+    select! {
+        _ = tokio::time::sleep(duration) => true,
+        _ = cancel.notified() => false,
+    }
+}
+
+async fn query_canceled(s: &mut dyn Stream) -> Result<(), Box<dyn Error + Send + Sync>> {
+    query_error(s, "57014", "canceling statement due to user request").await
+}
+
+/// Fails the running query. Unlike [`error_response`], the session stays usable.
+async fn query_error(
+    s: &mut dyn Stream,
+    code: &str,
+    message: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let fields = format!("SERROR\0VERROR\0C{code}\0M{message}\0\0");
+    write_message(s, b'E', &[fields.as_bytes()]).await
+}
+
+/// Splits a packet into big endian integers, ignoring any remainder.
+fn read_i32s(packet: &[u8]) -> Vec<i32> {
+    packet
+        .chunks_exact(4)
+        .map(|chunk| i32::from_be_bytes(chunk.try_into().unwrap()))
+        .collect()
+}
+
+/// Sends a FATAL ErrorResponse, after which the connection is closed.
+async fn error_response(
+    s: &mut dyn Stream,
+    code: &str,
+    message: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let fields = format!("SFATAL\0VFATAL\0C{code}\0M{message}\0\0");
+    write_message(s, b'E', &[fields.as_bytes()]).await
+}
+
 async fn read_packet(
-    s: &mut TcpStream,
+    s: &mut dyn Stream,
     buf: &mut BytesMut,
     prefix: usize,
 ) -> Result<Bytes, Box<dyn Error + Send + Sync>> {
     loop {
         if buf.len() >= 4 + prefix {
-            let len = u32::from_be_bytes(buf[prefix..4 + prefix].try_into().unwrap()) as usize + prefix;
+            let len = u32::from_be_bytes(buf[prefix..4 + prefix].try_into().unwrap()) as usize;
+            if len < 4 {
+                break Err("invalid message length".into());
+            }
+            let len = len + prefix;
             if buf.len() >= len {
                 break Ok(buf.split_to(len).freeze());
             }
         }
-        if s.read_buf(buf).await.unwrap() == 0 {
+        if s.read_buf(buf).await? == 0 {
             break Err("eof".into());
         }
     }
@@ -0,0 +1,484 @@
+//! Server side of SCRAM-SHA-256, verifying clients against per-role secrets.
+
+use hmac::{Hmac, Mac};
+use rand::{thread_rng, RngCore};
+use sha2::{Digest, Sha256};
+
+/// Like Postgres and cplane-mock.
+pub const DEFAULT_ITERATIONS: u32 = 4096;
+
+/// What the server stores for a role, like `pg_authid.rolpassword`.
+pub struct Secret {
+    iterations: u32,
+    salt: String,
+    stored_key: [u8; 32],
+    server_key: [u8; 32],
+}
+
+impl Secret {
+    /// Parses `SCRAM-SHA-256$<iterations>:<salt>$<StoredKey>:<ServerKey>`.
+    pub fn parse(secret: &str) -> Option<Self> {
+        let rest = secret.strip_prefix("SCRAM-SHA-256$")?;
+        let (params, keys) = rest.split_once('$')?;
+        let (iterations, salt) = params.split_once(':')?;
+        let (stored_key, server_key) = keys.split_once(':')?;
+        base64::decode(salt).ok()?;
+        Some(Self {
+            iterations: iterations.parse().ok().filter(|&n| n > 0)?,
+            salt: salt.to_owned(),
+            stored_key: base64::decode(stored_key).ok()?.try_into().ok()?,
+            server_key: base64::decode(server_key).ok()?.try_into().ok()?,
+        })
+    }
+
+    /// Derives the secret. `salt` is base64 and already validated.
+    pub fn from_password(password: &str, iterations: u32, salt: &str) -> Self {
+        let salted_password = salted_password(password, salt, iterations);
+        Self {
+            iterations,
+            salt: salt.to_owned(),
+            stored_key: Sha256::digest(hmac_sha256(&salted_password, b"Client Key")).into(),
+            server_key: hmac_sha256(&salted_password, b"Server Key"),
+        }
+    }
+
+    /// Checks a cleartext password, which costs the same PBKDF2 run as deriving the secret.
+    pub fn verify_password(&self, password: &str) -> bool {
+        let salted_password = salted_password(password, &self.salt, self.iterations);
+        Sha256::digest(hmac_sha256(&salted_password, b"Client Key")).as_slice() == self.stored_key
+    }
+}
+
+/// `salt` is base64 and already validated.
+fn salted_password(password: &str, salt: &str, iterations: u32) -> [u8; 32] {
+    let salt = base64::decode(salt).unwrap();
+    let mut salted_password = [0; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, iterations, &mut salted_password);
+    salted_password
+}
+
+pub const SCRAM_SHA_256: &str = "SCRAM-SHA-256";
+pub const SCRAM_SHA_256_PLUS: &str = "SCRAM-SHA-256-PLUS";
+
+/// The only channel binding type Postgres supports.
+const TLS_SERVER_END_POINT: &str = "tls-server-end-point";
+
+/// The gs2 header's channel binding flag.
+#[derive(Debug, PartialEq, Eq)]
+enum ChannelBindingFlag {
+    /// `n`: the client doesn't support channel binding.
+    Unsupported,
+    /// `y`: the client supports it but thinks the server doesn't.
+    NotOffered,
+    /// `p=<type>`: the client uses channel binding.
+    Required(String),
+}
+
+/// A parsed client-first-message.
+struct ClientFirst<'m> {
+    flag: ChannelBindingFlag,
+    /// Echoed back in the client-final-message's `c=` attribute.
+    gs2_header: &'m str,
+    bare: &'m str,
+    nonce: &'m str,
+}
+
+impl<'m> ClientFirst<'m> {
+    fn parse(message: &'m str) -> Result<Self, String> {
+        let mut gs2 = message.splitn(3, ',');
+        let (Some(flag), Some(authzid), Some(bare)) = (gs2.next(), gs2.next(), gs2.next()) else {
+            return Err("malformed SCRAM message: missing gs2 header".to_owned());
+        };
+        let flag = match flag {
+            "n" => ChannelBindingFlag::Unsupported,
+            "y" => ChannelBindingFlag::NotOffered,
+            _ => match flag.strip_prefix("p=") {
+                Some(name) if !name.is_empty() => ChannelBindingFlag::Required(name.to_owned()),
+                _ => {
+                    return Err(format!(
+                        "malformed SCRAM message: channel binding flag {flag}"
+                    ))
+                }
+            },
+        };
+        // like Postgres, which always authenticates the startup message's user
+        if !authzid.is_empty() {
+            return Err("client uses authorization identity, but it is not supported".to_owned());
+        }
+        let gs2_header = &message[..message.len() - bare.len()];
+
+        let mut attrs = bare.split(',');
+        let username = match attrs.next() {
+            Some(attr) if attr.starts_with("m=") => {
+                return Err("unsupported SCRAM extension".to_owned())
+            }
+            Some(attr) => attr
+                .strip_prefix("n=")
+                .ok_or("malformed SCRAM message: expected username")?,
+            None => unreachable!("split yields at least one item"),
+        };
+        // the username is ignored, but must be a valid saslname
+        decode_saslname(username)?;
+        let nonce = attrs
+            .next()
+            .and_then(|attr| attr.strip_prefix("r="))
+            .ok_or("malformed SCRAM message: expected nonce")?;
+        if nonce.is_empty() || !nonce.bytes().all(is_printable) {
+            return Err("malformed SCRAM message: invalid nonce".to_owned());
+        }
+        // later attributes are optional extensions, which we don't support and may ignore
+
+        Ok(Self {
+            flag,
+            gs2_header,
+            bare,
+            nonce,
+        })
+    }
+}
+
+/// Decodes `=2C` and `=3D`, rejecting any other `=`.
+fn decode_saslname(name: &str) -> Result<String, String> {
+    let mut decoded = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(i) = rest.find('=') {
+        decoded.push_str(&rest[..i]);
+        match rest.get(i..i + 3) {
+            Some("=2C") => decoded.push(','),
+            Some("=3D") => decoded.push('='),
+            _ => return Err("malformed SCRAM message: invalid username encoding".to_owned()),
+        }
+        rest = &rest[i + 3..];
+    }
+    decoded.push_str(rest);
+    Ok(decoded)
+}
+
+/// Printable ASCII except `,`, as allowed in nonces.
+fn is_printable(b: u8) -> bool {
+    (0x21..=0x7e).contains(&b) && b != b','
+}
+
+/// One SCRAM exchange, from the client-first-message to the server-final-message.
+pub struct Exchange<'a> {
+    /// `None` for unknown roles, which go through the exchange and then fail like a wrong password.
+    secret: Option<&'a Secret>,
+    /// Expected in the client-final-message: the gs2 header, then the channel binding data if used.
+    channel_binding: Vec<u8>,
+    client_first_bare: String,
+    server_first: String,
+    nonce: String,
+}
+
+impl<'a> Exchange<'a> {
+    /// Takes the mechanism the client picked and its client-first-message, and returns the
+    /// exchange with its server-first-message.
+    ///
+    /// `channel_binding` is the `tls-server-end-point` data of the connection, if it has TLS.
+    /// SCRAM-SHA-256-PLUS should only be offered then.
+    pub fn start(
+        secret: Option<&'a Secret>,
+        mechanism: &str,
+        client_first: &str,
+        channel_binding: Option<&[u8]>,
+    ) -> Result<Self, String> {
+        let client_first = ClientFirst::parse(client_first)?;
+        let mut expected_binding = client_first.gs2_header.as_bytes().to_vec();
+        match (mechanism, &client_first.flag, channel_binding) {
+            (SCRAM_SHA_256_PLUS, ChannelBindingFlag::Required(name), Some(data)) => {
+                if name != TLS_SERVER_END_POINT {
+                    return Err(format!("unsupported SCRAM channel binding type {name}"));
+                }
+                expected_binding.extend_from_slice(data);
+            }
+            (SCRAM_SHA_256_PLUS, _, Some(_)) => {
+                return Err(
+                    "channel binding is required with SCRAM-SHA-256-PLUS, but the client \
+                     doesn't use it"
+                        .to_owned(),
+                )
+            }
+            (SCRAM_SHA_256, ChannelBindingFlag::Required(_), _) => {
+                return Err(
+                    "the client selected SCRAM-SHA-256 without channel binding, but the SCRAM \
+                     message includes channel binding data"
+                        .to_owned(),
+                )
+            }
+            (SCRAM_SHA_256, ChannelBindingFlag::NotOffered, Some(_)) => {
+                // we offered SCRAM-SHA-256-PLUS, so something stripped it on the way
+                return Err("SCRAM channel binding negotiation error".to_owned());
+            }
+            (SCRAM_SHA_256, _, _) => {}
+            _ => return Err(format!("unsupported SASL mechanism {mechanism}")),
+        }
+
+        let mut server_nonce = [0; 18];
+        thread_rng().fill_bytes(&mut server_nonce);
+        let nonce = format!("{}{}", client_first.nonce, base64::encode(server_nonce));
+
+        // unknown roles get a made up salt, so the exchange doesn't tell them apart
+        let (salt, iterations) = match secret {
+            Some(secret) => (secret.salt.clone(), secret.iterations),
+            None => (random_salt(), DEFAULT_ITERATIONS),
+        };
+        Ok(Self {
+            secret,
+            channel_binding: expected_binding,
+            client_first_bare: client_first.bare.to_owned(),
+            server_first: format!("r={nonce},s={salt},i={iterations}"),
+            nonce,
+        })
+    }
+
+    pub fn server_first(&self) -> &str {
+        &self.server_first
+    }
+
+    /// Checks the client-final-message and returns the server-final-message if the proof is valid.
+    pub fn finish(&self, client_final: &str) -> Result<Option<String>, String> {
+        let (without_proof, proof) = client_final
+            .rsplit_once(",p=")
+            .ok_or("malformed SCRAM message: expected proof")?;
+        let mut attrs = without_proof.split(',');
+        let channel_binding = attrs
+            .next()
+            .and_then(|attr| attr.strip_prefix("c="))
+            .ok_or("malformed SCRAM message: expected channel binding")?;
+        let channel_binding = base64::decode(channel_binding)
+            .map_err(|_| "malformed SCRAM message: invalid channel binding")?;
+        if channel_binding != self.channel_binding {
+            return Err("SCRAM channel binding check failed".to_owned());
+        }
+        if attrs.next().and_then(|attr| attr.strip_prefix("r=")) != Some(&self.nonce) {
+            return Err("SCRAM nonce doesn't match".to_owned());
+        }
+        // any further attributes are extensions, ignored like the ones in the first message
+        let proof: [u8; 32] = base64::decode(proof)
+            .ok()
+            .and_then(|proof| proof.try_into().ok())
+            .ok_or("malformed SCRAM message: invalid proof")?;
+
+        let Some(secret) = self.secret else {
+            return Ok(None);
+        };
+        let auth_message = format!(
+            "{},{},{without_proof}",
+            self.client_first_bare, self.server_first
+        );
+        let client_signature = hmac_sha256(&secret.stored_key, auth_message.as_bytes());
+        let mut client_key = proof;
+        for (k, s) in client_key.iter_mut().zip(client_signature) {
+            *k ^= s;
+        }
+        if Sha256::digest(client_key).as_slice() != secret.stored_key {
+            return Ok(None);
+        }
+
+        let server_signature = hmac_sha256(&secret.server_key, auth_message.as_bytes());
+        Ok(Some(format!("v={}", base64::encode(server_signature))))
+    }
+}
+
+/// 16 random bytes, base64 encoded.
+pub fn random_salt() -> String {
+    let mut salt = [0; 16];
+    thread_rng().fill_bytes(&mut salt);
+    base64::encode(salt)
+}
+
+fn hmac_sha256(key: &[u8], msg: &[u8]) -> [u8; 32] {
+    Hmac::<Sha256>::new_from_slice(key)
+        .unwrap()
+        .chain_update(msg)
+        .finalize()
+        .into_bytes()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What cplane-mock's `scram_secret` returns for `hunter2` with this salt, checked there too.
+    const CPLANE_SECRET: &str = "SCRAM-SHA-256$4096:c2FsdHNhbHRzYWx0c2FsdA==$q8t7J+WVNsRr6Tu06Ubd5gdShr+OmOy+x7A3A22eiFI=:yo5XYL09RIjhOLG0BUQ1/Yy5T0QPImxAWUz2IYFjEsk=";
+
+    const END_POINT: &[u8] = b"certificate hash";
+
+    /// Runs the client side of the exchange, returning the client-final-message and the
+    /// server-final-message the client expects.
+    fn respond(
+        exchange: &Exchange,
+        password: &str,
+        client_first: &str,
+        channel_binding: &[u8],
+    ) -> (String, String) {
+        let client_first_bare = ClientFirst::parse(client_first).unwrap().bare;
+        let server_first = exchange.server_first();
+        let mut attrs = server_first.split(',');
+        let nonce = attrs.next().unwrap().strip_prefix("r=").unwrap();
+        let salt = attrs.next().unwrap().strip_prefix("s=").unwrap();
+        let iterations = attrs.next().unwrap().strip_prefix("i=").unwrap();
+
+        let salted_password = salted_password(password, salt, iterations.parse().unwrap());
+        let client_key = hmac_sha256(&salted_password, b"Client Key");
+        let stored_key = Sha256::digest(client_key);
+        let without_proof = format!("c={},r={nonce}", base64::encode(channel_binding));
+        let auth_message = format!("{client_first_bare},{server_first},{without_proof}");
+        let mut proof = client_key;
+        for (p, s) in proof
+            .iter_mut()
+            .zip(hmac_sha256(&stored_key, auth_message.as_bytes()))
+        {
+            *p ^= s;
+        }
+        let server_key = hmac_sha256(&salted_password, b"Server Key");
+        let server_signature = hmac_sha256(&server_key, auth_message.as_bytes());
+        (
+            format!("{without_proof},p={}", base64::encode(proof)),
+            format!("v={}", base64::encode(server_signature)),
+        )
+    }
+
+    #[test]
+    fn gs2_headers() {
+        let first = ClientFirst::parse("n,,n=,r=abc").unwrap();
+        assert_eq!(first.flag, ChannelBindingFlag::Unsupported);
+        assert_eq!(
+            (first.gs2_header, first.bare, first.nonce),
+            ("n,,", "n=,r=abc", "abc")
+        );
+
+        let first = ClientFirst::parse("y,,n=,r=abc").unwrap();
+        assert_eq!(first.flag, ChannelBindingFlag::NotOffered);
+        assert_eq!(first.gs2_header, "y,,");
+
+        let first = ClientFirst::parse("p=tls-server-end-point,,n=,r=abc,x=ext").unwrap();
+        assert_eq!(
+            first.flag,
+            ChannelBindingFlag::Required(TLS_SERVER_END_POINT.to_owned())
+        );
+        assert_eq!(first.gs2_header, "p=tls-server-end-point,,");
+        assert_eq!(first.bare, "n=,r=abc,x=ext");
+
+        for message in [
+            "n,n=,r=abc",
+            "x,,n=,r=abc",
+            "p=,,n=,r=abc",
+            "n,a=admin,n=,r=abc",
+            "n,,m=ext,n=,r=abc",
+            "n,,r=abc",
+            "n,,n=",
+            "n,,n=,r=",
+            "n,,n=,r=a b",
+        ] {
+            assert!(ClientFirst::parse(message).is_err(), "{message}");
+        }
+    }
+
+    #[test]
+    fn saslnames() {
+        assert_eq!(decode_saslname("plain").unwrap(), "plain");
+        assert_eq!(decode_saslname("a=2Cb=3Dc").unwrap(), "a,b=c");
+        assert_eq!(decode_saslname("=3D=2C").unwrap(), "=,");
+        for name in ["a=", "a=2", "a=41", "a=2c", "a=3d"] {
+            assert!(decode_saslname(name).is_err(), "{name}");
+        }
+
+        assert!(ClientFirst::parse("n,,n=user=2Cname,r=abc").is_ok());
+        assert!(ClientFirst::parse("n,,n=user=name,r=abc").is_err());
+    }
+
+    #[test]
+    fn channel_binding_negotiation() {
+        let secret = Secret::parse(CPLANE_SECRET).unwrap();
+        let start = |mechanism, client_first, tls| {
+            Exchange::start(Some(&secret), mechanism, client_first, tls).is_ok()
+        };
+        let plus = "p=tls-server-end-point,,n=,r=abc";
+        let tls = Some(END_POINT);
+
+        // without TLS only SCRAM-SHA-256 is offered
+        assert!(start(SCRAM_SHA_256, "n,,n=,r=abc", None));
+        assert!(start(SCRAM_SHA_256, "y,,n=,r=abc", None));
+        assert!(!start(SCRAM_SHA_256, plus, None));
+        assert!(!start(SCRAM_SHA_256_PLUS, plus, None));
+
+        // with TLS a client that supports channel binding must use it
+        assert!(start(SCRAM_SHA_256, "n,,n=,r=abc", tls));
+        assert!(!start(SCRAM_SHA_256, "y,,n=,r=abc", tls));
+        assert!(!start(SCRAM_SHA_256, plus, tls));
+        assert!(start(SCRAM_SHA_256_PLUS, plus, tls));
+        assert!(!start(SCRAM_SHA_256_PLUS, "n,,n=,r=abc", tls));
+        assert!(!start(SCRAM_SHA_256_PLUS, "y,,n=,r=abc", tls));
+        assert!(!start(SCRAM_SHA_256_PLUS, "p=tls-unique,,n=,r=abc", tls));
+
+        assert!(!start("SCRAM-SHA-1", "n,,n=,r=abc", None));
+    }
+
+    #[test]
+    fn round_trip_with_cplane_secret() {
+        let secret = Secret::parse(CPLANE_SECRET).unwrap();
+        assert!(secret.verify_password("hunter2"));
+        assert!(!secret.verify_password("hunter3"));
+
+        let client_first = "n,,n=,r=clientnonce";
+        let exchange = Exchange::start(Some(&secret), SCRAM_SHA_256, client_first, None).unwrap();
+        assert!(exchange.server_first().starts_with("r=clientnonce"));
+        assert!(exchange
+            .server_first()
+            .ends_with(",s=c2FsdHNhbHRzYWx0c2FsdA==,i=4096"));
+        let (client_final, server_final) = respond(&exchange, "hunter2", client_first, b"n,,");
+        assert_eq!(exchange.finish(&client_final), Ok(Some(server_final)));
+    }
+
+    #[test]
+    fn round_trip_with_channel_binding() {
+        let secret = Secret::from_password("hunter2", 16, &random_salt());
+        let client_first = "p=tls-server-end-point,,n=,r=clientnonce";
+        let exchange = Exchange::start(
+            Some(&secret),
+            SCRAM_SHA_256_PLUS,
+            client_first,
+            Some(END_POINT),
+        )
+        .unwrap();
+        let binding = [b"p=tls-server-end-point,,".as_slice(), END_POINT].concat();
+        let (client_final, server_final) = respond(&exchange, "hunter2", client_first, &binding);
+        assert_eq!(exchange.finish(&client_final), Ok(Some(server_final)));
+
+        // another certificate, e.g. a man in the middle
+        let binding = [b"p=tls-server-end-point,,".as_slice(), b"other hash"].concat();
+        let (client_final, _) = respond(&exchange, "hunter2", client_first, &binding);
+        assert!(exchange.finish(&client_final).is_err());
+
+        // the gs2 header alone, as without channel binding
+        let (client_final, _) = respond(&exchange, "hunter2", client_first, b"n,,");
+        assert!(exchange.finish(&client_final).is_err());
+    }
+
+    #[test]
+    fn wrong_proofs() {
+        let secret = Secret::parse(CPLANE_SECRET).unwrap();
+        let client_first = "n,,n=,r=clientnonce";
+        let exchange = Exchange::start(Some(&secret), SCRAM_SHA_256, client_first, None).unwrap();
+
+        let (client_final, _) = respond(&exchange, "hunter3", client_first, b"n,,");
+        assert_eq!(exchange.finish(&client_final), Ok(None));
+
+        // unknown roles fail the same way, whatever the password
+        let unknown = Exchange::start(None, SCRAM_SHA_256, client_first, None).unwrap();
+        let (client_final, _) = respond(&unknown, "hunter2", client_first, b"n,,");
+        assert_eq!(unknown.finish(&client_final), Ok(None));
+
+        // the client must echo the whole nonce, and send a proof of the right length
+        let (client_final, _) = respond(&exchange, "hunter2", client_first, b"n,,");
+        let other_nonce = client_final.replacen("r=clientnonce", "r=othernonce", 1);
+        assert!(exchange.finish(&other_nonce).is_err());
+        let (without_proof, _) = client_final.rsplit_once(",p=").unwrap();
+        assert!(exchange
+            .finish(&format!("{without_proof},p=c2hvcnQ="))
+            .is_err());
+        assert!(exchange.finish(without_proof).is_err());
+    }
+}
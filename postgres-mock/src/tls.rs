@@ -0,0 +1,41 @@
+use std::sync::Arc;
+
+use rustls::ServerConfig;
+use sha2::{Digest, Sha256};
+use tokio_rustls::TlsAcceptor;
+
+/// ALPN protocol of direct TLS connections, which skip the SSLRequest.
+pub const ALPN_POSTGRESQL: &[u8] = b"postgresql";
+
+/// Server TLS from `$POSTGRES_MOCK_TLS_CERT` and `$POSTGRES_MOCK_TLS_KEY`, e.g. the ones `tls.sh`
+/// writes to `target/`.
+pub struct Tls {
+    pub acceptor: TlsAcceptor,
+    /// `tls-server-end-point` channel binding data: the hash of the certificate. Assumes it's
+    /// signed with SHA-256, like the ones `tls.sh` writes.
+    pub server_end_point: Vec<u8>,
+}
+
+impl Tls {
+    pub fn from_env() -> Option<Self> {
+        let cert_path = std::env::var("POSTGRES_MOCK_TLS_CERT").ok()?;
+        let key_path = std::env::var("POSTGRES_MOCK_TLS_KEY")
+            .expect("POSTGRES_MOCK_TLS_KEY must be set with POSTGRES_MOCK_TLS_CERT");
+
+        let (certs, key) = mock_tls::load_pem(&cert_path, &key_path);
+        let server_end_point = Sha256::digest(&certs[0]).to_vec();
+
+        let mut config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .expect("certificate and key must match");
+        // clients negotiating TLS with an SSLRequest may leave ALPN out
+        config.alpn_protocols = vec![ALPN_POSTGRESQL.to_vec()];
+        println!("Serving TLS with {cert_path}");
+
+        Some(Self {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            server_end_point,
+        })
+    }
+}
@@ -0,0 +1,782 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use bytes::{Bytes, BytesMut};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    select,
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+    time::Instant,
+};
+
+/// Just enough of Redis for the proxy: keys, hashes, expiry and pub/sub, over RESP2.
+#[tokio::main]
+async fn main() {
+    let mut signal = signal(SignalKind::terminate()).unwrap();
+    let addr = std::env::var("REDIS_MOCK_ADDR").unwrap_or_else(|_| "0.0.0.0:6379".to_owned());
+    let listener = TcpListener::bind(&addr).await.unwrap();
+    println!("Listening on {addr}");
+    let db = Arc::new(Db::default());
+    tokio::spawn(expire_keys(db.clone()));
+    loop {
+        select! {
+            s = listener.accept() => tokio::spawn(handle(s.unwrap().0, db.clone())),
+            _ = signal.recv() => break,
+        };
+    }
+}
+
+#[derive(Default)]
+struct Db {
+    keys: Mutex<HashMap<Bytes, Entry>>,
+    /// channel -> subscribed connections
+    channels: Mutex<HashMap<Bytes, HashMap<u64, mpsc::UnboundedSender<Reply>>>>,
+    next_client_id: AtomicU64,
+}
+
+struct Entry {
+    value: Value,
+    expires_at: Option<Instant>,
+}
+
+enum Value {
+    String(Bytes),
+    Hash(HashMap<Bytes, Bytes>),
+}
+
+impl Entry {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+/// Drops expired keys in the background, so short lived keys don't pile up.
+async fn expire_keys(db: Arc<Db>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        let now = interval.tick().await;
+        db.keys.lock().unwrap().retain(|_, e| !e.is_expired(now));
+    }
+}
+
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Int(i64),
+    Bulk(Option<Bytes>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn encode(&self, out: &mut BytesMut) {
+        match self {
+            Reply::Simple(s) => out.extend_from_slice(format!("+{s}\r\n").as_bytes()),
+            Reply::Error(e) => out.extend_from_slice(format!("-{e}\r\n").as_bytes()),
+            Reply::Int(i) => out.extend_from_slice(format!(":{i}\r\n").as_bytes()),
+            Reply::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Bulk(Some(b)) => {
+                out.extend_from_slice(format!("${}\r\n", b.len()).as_bytes());
+                out.extend_from_slice(b);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(out);
+                }
+            }
+        }
+    }
+
+    fn bulk(b: impl Into<Bytes>) -> Self {
+        Reply::Bulk(Some(b.into()))
+    }
+}
+
+/// Most arguments in a command, like Redis.
+const MAX_ARGS: i64 = 1024 * 1024;
+/// Longest bulk string, Redis' default `proto-max-bulk-len`.
+const MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+
+const WRONGTYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+struct Connection {
+    id: u64,
+    tx: mpsc::UnboundedSender<Reply>,
+    subscriptions: HashSet<Bytes>,
+    /// Commands queued since MULTI.
+    multi: Option<Vec<Vec<Bytes>>>,
+}
+
+async fn handle(mut s: TcpStream, db: Arc<Db>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut conn = Connection {
+        id: db.next_client_id.fetch_add(1, Ordering::Relaxed),
+        tx,
+        subscriptions: HashSet::new(),
+        multi: None,
+    };
+
+    let mut buf = BytesMut::new();
+    let mut out = BytesMut::new();
+    let res = 'conn: loop {
+        loop {
+            match parse_command(&mut buf) {
+                Ok(Some(args)) if args.is_empty() => {}
+                Ok(Some(args)) => {
+                    for reply in conn.exec(&db, args) {
+                        reply.encode(&mut out);
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    Reply::Error(format!("ERR Protocol error: {e}")).encode(&mut out);
+                    let _ = s.write_all(&out).await;
+                    break 'conn Err(e);
+                }
+            }
+        }
+        if !out.is_empty() {
+            if let Err(e) = s.write_all(&out).await {
+                break Err(e.into());
+            }
+            out.clear();
+        }
+
+        select! {
+            n = s.read_buf(&mut buf) => match n {
+                Ok(0) => break Ok(()),
+                Ok(_) => {}
+                Err(e) => break Err(e.into()),
+            },
+            Some(message) = rx.recv() => message.encode(&mut out),
+        }
+    };
+
+    let mut channels = db.channels.lock().unwrap();
+    for channel in &conn.subscriptions {
+        if let Some(subscribers) = channels.get_mut(channel) {
+            subscribers.remove(&conn.id);
+        }
+    }
+    channels.retain(|_, subscribers| !subscribers.is_empty());
+
+    res
+}
+
+/// Parses one command, either a RESP array of bulk strings or an inline command.
+///
+/// Returns `None` until the buffer holds a complete command.
+fn parse_command(buf: &mut BytesMut) -> Result<Option<Vec<Bytes>>, Box<dyn Error + Send + Sync>> {
+    let Some(line_end) = find_crlf(buf, 0) else {
+        return Ok(None);
+    };
+
+    if buf[0] != b'*' {
+        let line = buf.split_to(line_end + 2).freeze();
+        let args = line[..line_end]
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| line.slice_ref(arg))
+            .collect();
+        return Ok(Some(args));
+    }
+
+    let count = parse_int(&buf[1..line_end])?;
+    if count > MAX_ARGS {
+        return Err("invalid multibulk length".into());
+    }
+    let mut pos = line_end + 2;
+    let mut spans = Vec::new();
+    for _ in 0..count {
+        let Some(end) = find_crlf(buf, pos) else {
+            return Ok(None);
+        };
+        if buf[pos] != b'$' {
+            return Err("expected bulk string".into());
+        }
+        let len = parse_int(&buf[pos + 1..end])?;
+        if !(0..=MAX_BULK_LEN).contains(&len) {
+            return Err("invalid bulk length".into());
+        }
+        let len = len as usize;
+        let start = end + 2;
+        if buf.len() < start + len + 2 {
+            return Ok(None);
+        }
+        spans.push(start..start + len);
+        pos = start + len + 2;
+    }
+
+    let frame = buf.split_to(pos).freeze();
+    Ok(Some(spans.into_iter().map(|s| frame.slice(s)).collect()))
+}
+
+fn find_crlf(buf: &[u8], from: usize) -> Option<usize> {
+    buf.get(from..)?
+        .windows(2)
+        .position(|w| w == b"\r\n")
+        .map(|i| from + i)
+}
+
+fn parse_int(b: &[u8]) -> Result<i64, Box<dyn Error + Send + Sync>> {
+    Ok(std::str::from_utf8(b)?.parse()?)
+}
+
+fn arg_int(arg: &[u8]) -> Result<i64, Reply> {
+    parse_int(arg).map_err(|_| Reply::Error("ERR value is not an integer or out of range".into()))
+}
+
+/// When a key with a TTL of `n` seconds or milliseconds expires, `None` if that's out of range.
+fn expiry(now: Instant, n: u64, millis: bool) -> Option<Instant> {
+    let ttl = if millis {
+        Duration::from_millis(n)
+    } else {
+        Duration::from_secs(n)
+    };
+    now.checked_add(ttl)
+}
+
+impl Connection {
+    fn exec(&mut self, db: &Db, mut args: Vec<Bytes>) -> Vec<Reply> {
+        let name = args.remove(0).to_ascii_uppercase();
+
+        if let Some(queued) = &mut self.multi {
+            match &name[..] {
+                b"EXEC" => {
+                    let queued = self.multi.take().unwrap();
+                    let replies = queued
+                        .into_iter()
+                        .flat_map(|args| self.exec(db, args))
+                        .collect();
+                    return vec![Reply::Array(replies)];
+                }
+                b"DISCARD" => {
+                    self.multi = None;
+                    return vec![Reply::Simple("OK")];
+                }
+                b"MULTI" => return vec![Reply::Error("ERR MULTI calls can not be nested".into())],
+                _ => {
+                    args.insert(0, Bytes::from(name));
+                    queued.push(args);
+                    return vec![Reply::Simple("QUEUED")];
+                }
+            }
+        }
+
+        match &name[..] {
+            b"SUBSCRIBE" => self.subscribe(db, args),
+            b"UNSUBSCRIBE" => self.unsubscribe(db, args),
+            b"MULTI" => {
+                self.multi = Some(vec![]);
+                vec![Reply::Simple("OK")]
+            }
+            b"EXEC" | b"DISCARD" => {
+                vec![Reply::Error(format!(
+                    "ERR {} without MULTI",
+                    String::from_utf8_lossy(&name)
+                ))]
+            }
+            _ => vec![run(db, &name, &args).unwrap_or_else(|e| e)],
+        }
+    }
+
+    fn subscribe(&mut self, db: &Db, channels: Vec<Bytes>) -> Vec<Reply> {
+        let mut subscribers = db.channels.lock().unwrap();
+        channels
+            .into_iter()
+            .map(|channel| {
+                subscribers
+                    .entry(channel.clone())
+                    .or_default()
+                    .insert(self.id, self.tx.clone());
+                self.subscriptions.insert(channel.clone());
+                Reply::Array(vec![
+                    Reply::bulk("subscribe"),
+                    Reply::bulk(channel),
+                    Reply::Int(self.subscriptions.len() as i64),
+                ])
+            })
+            .collect()
+    }
+
+    fn unsubscribe(&mut self, db: &Db, mut channels: Vec<Bytes>) -> Vec<Reply> {
+        if channels.is_empty() {
+            channels = self.subscriptions.iter().cloned().collect();
+        }
+        let mut subscribers = db.channels.lock().unwrap();
+        channels
+            .into_iter()
+            .map(|channel| {
+                if let Some(s) = subscribers.get_mut(&channel) {
+                    s.remove(&self.id);
+                }
+                self.subscriptions.remove(&channel);
+                Reply::Array(vec![
+                    Reply::bulk("unsubscribe"),
+                    Reply::bulk(channel),
+                    Reply::Int(self.subscriptions.len() as i64),
+                ])
+            })
+            .collect()
+    }
+}
+
+/// Looks up a key, dropping it first if it has expired.
+fn live<'a>(
+    keys: &'a mut HashMap<Bytes, Entry>,
+    key: &Bytes,
+    now: Instant,
+) -> Option<&'a mut Entry> {
+    if keys.get(key).is_some_and(|e| e.is_expired(now)) {
+        keys.remove(key);
+    }
+    keys.get_mut(key)
+}
+
+/// Runs a command that doesn't depend on connection state.
+fn run(db: &Db, name: &[u8], args: &[Bytes]) -> Result<Reply, Reply> {
+    let arity = |n: usize| {
+        if args.len() < n {
+            Err(Reply::Error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                String::from_utf8_lossy(name).to_lowercase()
+            )))
+        } else {
+            Ok(())
+        }
+    };
+
+    let now = Instant::now();
+    let mut keys = db.keys.lock().unwrap();
+    let keys = &mut *keys;
+
+    let reply = match name {
+        b"PING" => match args.first() {
+            Some(msg) => Reply::bulk(msg.clone()),
+            None => Reply::Simple("PONG"),
+        },
+        b"ECHO" => {
+            arity(1)?;
+            Reply::bulk(args[0].clone())
+        }
+        // connection setup the client library may send, nothing to do for us
+        b"AUTH" | b"SELECT" | b"CLIENT" | b"READONLY" => Reply::Simple("OK"),
+        b"INFO" => Reply::bulk("# Server\r\nredis_version:7.2.0\r\nredis_mode:standalone\r\n"),
+        b"GET" => {
+            arity(1)?;
+            match live(keys, &args[0], now).map(|e| &e.value) {
+                None => Reply::Bulk(None),
+                Some(Value::String(v)) => Reply::bulk(v.clone()),
+                Some(Value::Hash(_)) => return Err(Reply::Error(WRONGTYPE.into())),
+            }
+        }
+        b"SET" => {
+            arity(2)?;
+            let mut expires_at = None;
+            let (mut nx, mut xx) = (false, false);
+            let mut opts = args[2..].iter();
+            while let Some(opt) = opts.next() {
+                match &opt.to_ascii_uppercase()[..] {
+                    b"NX" => nx = true,
+                    b"XX" => xx = true,
+                    unit @ (b"EX" | b"PX") => {
+                        let n = opts.next().ok_or(Reply::Error("ERR syntax error".into()))?;
+                        let n = arg_int(n)?;
+                        let at = if n > 0 {
+                            expiry(now, n as u64, unit == b"PX")
+                        } else {
+                            None
+                        };
+                        expires_at = Some(at.ok_or(Reply::Error(
+                            "ERR invalid expire time in 'set' command".into(),
+                        ))?);
+                    }
+                    _ => return Err(Reply::Error("ERR syntax error".into())),
+                }
+            }
+            let exists = live(keys, &args[0], now).is_some();
+            if (nx && exists) || (xx && !exists) {
+                Reply::Bulk(None)
+            } else {
+                keys.insert(
+                    args[0].clone(),
+                    Entry {
+                        value: Value::String(args[1].clone()),
+                        expires_at,
+                    },
+                );
+                Reply::Simple("OK")
+            }
+        }
+        b"DEL" | b"UNLINK" => {
+            arity(1)?;
+            let removed = args
+                .iter()
+                .filter(|key| live(keys, key, now).is_some() && keys.remove(*key).is_some())
+                .count();
+            Reply::Int(removed as i64)
+        }
+        b"EXISTS" => {
+            arity(1)?;
+            Reply::Int(
+                args.iter()
+                    .filter(|key| live(keys, key, now).is_some())
+                    .count() as i64,
+            )
+        }
+        b"EXPIRE" | b"PEXPIRE" => {
+            arity(2)?;
+            // a TTL that isn't positive expires the key right away
+            let n = arg_int(&args[1])?.max(0) as u64;
+            let expires_at = expiry(now, n, name == b"PEXPIRE").ok_or_else(|| {
+                Reply::Error(format!(
+                    "ERR invalid expire time in '{}' command",
+                    String::from_utf8_lossy(name).to_lowercase()
+                ))
+            })?;
+            match live(keys, &args[0], now) {
+                Some(entry) => {
+                    entry.expires_at = Some(expires_at);
+                    Reply::Int(1)
+                }
+                None => Reply::Int(0),
+            }
+        }
+        b"PERSIST" => {
+            arity(1)?;
+            match live(keys, &args[0], now) {
+                Some(entry) if entry.expires_at.is_some() => {
+                    entry.expires_at = None;
+                    Reply::Int(1)
+                }
+                _ => Reply::Int(0),
+            }
+        }
+        b"TTL" | b"PTTL" => {
+            arity(1)?;
+            match live(keys, &args[0], now) {
+                None => Reply::Int(-2),
+                Some(Entry {
+                    expires_at: None, ..
+                }) => Reply::Int(-1),
+                Some(Entry {
+                    expires_at: Some(at),
+                    ..
+                }) => {
+                    let left = *at - now;
+                    if name == b"TTL" {
+                        Reply::Int(left.as_secs_f64().round() as i64)
+                    } else {
+                        Reply::Int(left.as_millis() as i64)
+                    }
+                }
+            }
+        }
+        b"HSET" => {
+            if args.len() < 3 || args.len().is_multiple_of(2) {
+                return Err(Reply::Error(
+                    "ERR wrong number of arguments for 'hset' command".into(),
+                ));
+            }
+            if live(keys, &args[0], now).is_none() {
+                keys.insert(
+                    args[0].clone(),
+                    Entry {
+                        value: Value::Hash(HashMap::new()),
+                        expires_at: None,
+                    },
+                );
+            }
+            let Value::Hash(hash) = &mut keys.get_mut(&args[0]).unwrap().value else {
+                return Err(Reply::Error(WRONGTYPE.into()));
+            };
+            let added = args[1..]
+                .chunks(2)
+                .filter(|kv| hash.insert(kv[0].clone(), kv[1].clone()).is_none())
+                .count();
+            Reply::Int(added as i64)
+        }
+        b"HGET" | b"HEXISTS" => {
+            arity(2)?;
+            let field = match live(keys, &args[0], now).map(|e| &e.value) {
+                None => None,
+                Some(Value::Hash(hash)) => hash.get(&args[1]).cloned(),
+                Some(Value::String(_)) => return Err(Reply::Error(WRONGTYPE.into())),
+            };
+            if name == b"HGET" {
+                Reply::Bulk(field)
+            } else {
+                Reply::Int(field.is_some() as i64)
+            }
+        }
+        b"HGETALL" | b"HLEN" => {
+            arity(1)?;
+            let pairs: Vec<_> = match live(keys, &args[0], now).map(|e| &e.value) {
+                None => vec![],
+                Some(Value::Hash(hash)) => hash.iter().collect(),
+                Some(Value::String(_)) => return Err(Reply::Error(WRONGTYPE.into())),
+            };
+            if name == b"HLEN" {
+                Reply::Int(pairs.len() as i64)
+            } else {
+                Reply::Array(
+                    pairs
+                        .into_iter()
+                        .flat_map(|(k, v)| [Reply::bulk(k.clone()), Reply::bulk(v.clone())])
+                        .collect(),
+                )
+            }
+        }
+        b"HDEL" => {
+            arity(2)?;
+            let removed = match live(keys, &args[0], now).map(|e| &mut e.value) {
+                None => 0,
+                Some(Value::Hash(hash)) => args[1..]
+                    .iter()
+                    .filter(|f| hash.remove(*f).is_some())
+                    .count(),
+                Some(Value::String(_)) => return Err(Reply::Error(WRONGTYPE.into())),
+            };
+            if matches!(&keys.get(&args[0]).map(|e| &e.value), Some(Value::Hash(h)) if h.is_empty())
+            {
+                keys.remove(&args[0]);
+            }
+            Reply::Int(removed as i64)
+        }
+        b"DBSIZE" => Reply::Int(keys.values().filter(|e| !e.is_expired(now)).count() as i64),
+        b"FLUSHALL" | b"FLUSHDB" => {
+            keys.clear();
+            Reply::Simple("OK")
+        }
+        b"PUBLISH" => {
+            arity(2)?;
+            let channels = db.channels.lock().unwrap();
+            let receivers = channels.get(&args[0]).map_or(0, |subscribers| {
+                subscribers
+                    .values()
+                    .filter(|tx| {
+                        tx.send(Reply::Array(vec![
+                            Reply::bulk("message"),
+                            Reply::bulk(args[0].clone()),
+                            Reply::bulk(args[1].clone()),
+                        ]))
+                        .is_ok()
+                    })
+                    .count()
+            });
+            Reply::Int(receivers as i64)
+        }
+        _ => Reply::Error(format!(
+            "ERR unknown command '{}'",
+            String::from_utf8_lossy(name)
+        )),
+    };
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connect(db: &Db) -> (Connection, mpsc::UnboundedReceiver<Reply>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let conn = Connection {
+            id: db.next_client_id.fetch_add(1, Ordering::Relaxed),
+            tx,
+            subscriptions: HashSet::new(),
+            multi: None,
+        };
+        (conn, rx)
+    }
+
+    /// Runs an inline command and returns the encoded replies.
+    fn exec(conn: &mut Connection, db: &Db, command: &str) -> String {
+        let mut buf = BytesMut::from(format!("{command}\r\n").as_str());
+        let args = parse_command(&mut buf).unwrap().unwrap();
+        encode(conn.exec(db, args))
+    }
+
+    fn encode(replies: impl IntoIterator<Item = Reply>) -> String {
+        let mut out = BytesMut::new();
+        for reply in replies {
+            reply.encode(&mut out);
+        }
+        String::from_utf8(out.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn partial_frames() {
+        let frame = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nva\r\nl\r\n*1\r\n$4\r\nPING\r\n";
+        let first = frame.len() - b"*1\r\n$4\r\nPING\r\n".len();
+        for split in 1..first {
+            let mut buf = BytesMut::from(&frame[..split]);
+            assert!(
+                parse_command(&mut buf).unwrap().is_none(),
+                "split at {split}"
+            );
+            assert_eq!(buf.len(), split, "a partial frame is left in the buffer");
+
+            buf.extend_from_slice(&frame[split..]);
+            let args = parse_command(&mut buf).unwrap().unwrap();
+            assert_eq!(args, ["SET", "key", "va\r\nl"]);
+            assert_eq!(parse_command(&mut buf).unwrap().unwrap(), ["PING"]);
+            assert!(parse_command(&mut buf).unwrap().is_none());
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn inline_commands() {
+        let mut buf = BytesMut::from("PING\r\n  set   key\tvalue \r\n\r\nGET");
+        assert_eq!(parse_command(&mut buf).unwrap().unwrap(), ["PING"]);
+        assert_eq!(
+            parse_command(&mut buf).unwrap().unwrap(),
+            ["set", "key", "value"]
+        );
+        assert!(parse_command(&mut buf).unwrap().unwrap().is_empty());
+        assert!(parse_command(&mut buf).unwrap().is_none());
+
+        for invalid in [
+            "*x\r\n",
+            "*1\r\n:1\r\n",
+            "*1\r\n$-1\r\n",
+            "*9223372036854775807\r\n",
+            "*1\r\n$18446744073709551615\r\n",
+            "*1\r\n$9223372036854775807\r\n",
+        ] {
+            let mut buf = BytesMut::from(invalid);
+            assert!(parse_command(&mut buf).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn transactions() {
+        let db = Db::default();
+        let (mut conn, _rx) = connect(&db);
+
+        assert_eq!(exec(&mut conn, &db, "EXEC"), "-ERR EXEC without MULTI\r\n");
+        assert_eq!(exec(&mut conn, &db, "MULTI"), "+OK\r\n");
+        assert_eq!(
+            exec(&mut conn, &db, "MULTI"),
+            "-ERR MULTI calls can not be nested\r\n"
+        );
+        assert_eq!(exec(&mut conn, &db, "SET key value"), "+QUEUED\r\n");
+        assert_eq!(exec(&mut conn, &db, "get key"), "+QUEUED\r\n");
+        // nothing runs before EXEC
+        assert!(live(&mut db.keys.lock().unwrap(), &"key".into(), Instant::now()).is_none());
+        assert_eq!(exec(&mut conn, &db, "EXEC"), "*2\r\n+OK\r\n$5\r\nvalue\r\n");
+
+        assert_eq!(exec(&mut conn, &db, "MULTI"), "+OK\r\n");
+        assert_eq!(exec(&mut conn, &db, "DEL key"), "+QUEUED\r\n");
+        assert_eq!(exec(&mut conn, &db, "DISCARD"), "+OK\r\n");
+        assert_eq!(exec(&mut conn, &db, "GET key"), "$5\r\nvalue\r\n");
+        assert_eq!(
+            exec(&mut conn, &db, "DISCARD"),
+            "-ERR DISCARD without MULTI\r\n"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expiry() {
+        let db = Db::default();
+        let (mut conn, _rx) = connect(&db);
+
+        assert_eq!(exec(&mut conn, &db, "SET short value PX 1500"), "+OK\r\n");
+        assert_eq!(exec(&mut conn, &db, "SET long value EX 10"), "+OK\r\n");
+        assert_eq!(exec(&mut conn, &db, "HSET hash field value"), ":1\r\n");
+        assert_eq!(exec(&mut conn, &db, "EXPIRE hash 2"), ":1\r\n");
+        assert_eq!(exec(&mut conn, &db, "TTL short"), ":2\r\n");
+        assert_eq!(exec(&mut conn, &db, "PTTL long"), ":10000\r\n");
+        assert_eq!(exec(&mut conn, &db, "SET kept value EX 1"), "+OK\r\n");
+        assert_eq!(exec(&mut conn, &db, "PERSIST kept"), ":1\r\n");
+        assert_eq!(exec(&mut conn, &db, "TTL kept"), ":-1\r\n");
+
+        tokio::time::advance(Duration::from_millis(1500)).await;
+        assert_eq!(exec(&mut conn, &db, "GET short"), "$-1\r\n");
+        assert_eq!(exec(&mut conn, &db, "TTL short"), ":-2\r\n");
+        assert_eq!(exec(&mut conn, &db, "HGET hash field"), "$5\r\nvalue\r\n");
+        assert_eq!(
+            exec(&mut conn, &db, "EXISTS short long hash kept"),
+            ":3\r\n"
+        );
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert_eq!(exec(&mut conn, &db, "HGETALL hash"), "*0\r\n");
+        assert_eq!(exec(&mut conn, &db, "DBSIZE"), ":2\r\n");
+        // an expired key can be set again with NX
+        assert_eq!(exec(&mut conn, &db, "SET short again NX"), "+OK\r\n");
+        assert_eq!(exec(&mut conn, &db, "GET short"), "$5\r\nagain\r\n");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn invalid_expire_times() {
+        let db = Db::default();
+        let (mut conn, _rx) = connect(&db);
+
+        for ttl in ["EX 0", "PX 0", "EX -1", "EX 9223372036854775807"] {
+            assert_eq!(
+                exec(&mut conn, &db, &format!("SET key value {ttl}")),
+                "-ERR invalid expire time in 'set' command\r\n",
+                "{ttl}"
+            );
+        }
+        assert_eq!(exec(&mut conn, &db, "SET key value"), "+OK\r\n");
+        assert_eq!(
+            exec(&mut conn, &db, "EXPIRE key 9223372036854775807"),
+            "-ERR invalid expire time in 'expire' command\r\n"
+        );
+        // the keys are still usable afterwards
+        assert_eq!(exec(&mut conn, &db, "TTL key"), ":-1\r\n");
+        assert_eq!(
+            exec(&mut conn, &db, "PEXPIRE key 9223372036854775807"),
+            ":1\r\n"
+        );
+        assert_eq!(exec(&mut conn, &db, "EXPIRE key -5"), ":1\r\n");
+        assert_eq!(exec(&mut conn, &db, "GET key"), "$-1\r\n");
+    }
+
+    #[test]
+    fn publish_fan_out() {
+        let db = Db::default();
+        let (mut first, mut first_rx) = connect(&db);
+        let (mut second, mut second_rx) = connect(&db);
+        let (mut publisher, _rx) = connect(&db);
+
+        assert_eq!(
+            exec(&mut first, &db, "SUBSCRIBE updates other"),
+            "*3\r\n$9\r\nsubscribe\r\n$7\r\nupdates\r\n:1\r\n\
+             *3\r\n$9\r\nsubscribe\r\n$5\r\nother\r\n:2\r\n"
+        );
+        exec(&mut second, &db, "SUBSCRIBE updates");
+
+        assert_eq!(exec(&mut publisher, &db, "PUBLISH updates hello"), ":2\r\n");
+        let message = "*3\r\n$7\r\nmessage\r\n$7\r\nupdates\r\n$5\r\nhello\r\n";
+        assert_eq!(encode([first_rx.try_recv().unwrap()]), message);
+        assert_eq!(encode([second_rx.try_recv().unwrap()]), message);
+        assert!(first_rx.try_recv().is_err());
+
+        assert_eq!(exec(&mut publisher, &db, "PUBLISH nobody hello"), ":0\r\n");
+
+        assert_eq!(
+            exec(&mut second, &db, "UNSUBSCRIBE"),
+            "*3\r\n$11\r\nunsubscribe\r\n$7\r\nupdates\r\n:0\r\n"
+        );
+        assert_eq!(exec(&mut publisher, &db, "PUBLISH updates again"), ":1\r\n");
+        assert!(first_rx.try_recv().is_ok());
+        assert!(second_rx.try_recv().is_err());
+
+        // a subscriber that went away isn't counted
+        drop(first_rx);
+        assert_eq!(exec(&mut publisher, &db, "PUBLISH other hello"), ":0\r\n");
+    }
+}
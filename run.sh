@@ -18,6 +18,7 @@ NEON_PROXY_PATH="${NEON_PROXY_PATH:-proxy}"
 # Environment variables for load testing
 POSTGRES_MOCK_PORT="${POSTGRES_MOCK_PORT:-5432}"
 CPLANE_MOCK_PORT="${CPLANE_MOCK_PORT:-3010}"
+REDIS_MOCK_PORT="${REDIS_MOCK_PORT:-6379}"
 PROXY_PORT="${PROXY_PORT:-5433}"
 PROXY_WSS_PORT="${PROXY_WSS_PORT:-443}"
 PROXY_HTTP_PORT="${PROXY_HTTP_PORT:-8080}"
@@ -25,9 +26,20 @@ PROXY_HTTP_PORT="${PROXY_HTTP_PORT:-8080}"
 PG_CONNECTION_RATE="${PG_CONNECTION_RATE:-5}"
 PG_CONNECTING_MAX="${PG_CONNECTING_MAX:-10}"
 PG_CONNECTION_MAX="${PG_CONNECTION_MAX:-20}"
+PG_USER="${PG_USER:-demo}"
+PG_PASSWORD="${PG_PASSWORD:-password}"
+PG_PASSWORD_HACK_PERCENT="${PG_PASSWORD_HACK_PERCENT:-0}"
+PG_FOLLOW_CONSOLE_LINKS="${PG_FOLLOW_CONSOLE_LINKS:-false}"
 HTTP_CONNECTION_RATE="${HTTP_CONNECTION_RATE:-5}"
 HTTP_CONNECTION_MAX="${HTTP_CONNECTION_MAX:-5}"
 
+# Hand out compute hostnames in this domain, resolved by dns-mock (empty to hand out addresses)
+export COMPUTE_DOMAIN="${COMPUTE_DOMAIN:-}"
+DNS_MOCK_ADDR="${DNS_MOCK_ADDR:-127.0.0.1:53}"
+
+# SCRAM salt shared by cplane-mock and postgres-mock, so the proxy's keys match the compute's
+export SCRAM_SALT="${SCRAM_SALT:-$(head -c 16 /dev/urandom | base64)}"
+
 # Parse command line arguments
 while [[ $# -gt 0 ]]; do
     case $1 in
@@ -89,12 +101,26 @@ start_bare_metal_services() {
 
     # Start postgres-mock (it listens on port 5432 - hardcoded in source)
     echo "Starting postgres-mock on port 5432..."
-    RUST_LOG=info ./target/release/postgres-mock > logs/postgres-mock.log 2>&1 &
+    POSTGRES_MOCK_SCRAM_SALT="$SCRAM_SALT" RUST_LOG=info ./target/release/postgres-mock > logs/postgres-mock.log 2>&1 &
     POSTGRES_MOCK_PID=$!
     echo "postgres-mock started with PID $POSTGRES_MOCK_PID"
 
+    echo "Starting redis-mock on port $REDIS_MOCK_PORT..."
+    REDIS_MOCK_ADDR="0.0.0.0:$REDIS_MOCK_PORT" RUST_LOG=info ./target/release/redis-mock > logs/redis-mock.log 2>&1 &
+    REDIS_MOCK_PID=$!
+    echo "redis-mock started with PID $REDIS_MOCK_PID"
+
+    DNS_MOCK_PID=""
+    if [ -n "$COMPUTE_DOMAIN" ]; then
+        echo "Starting dns-mock on $DNS_MOCK_ADDR for $COMPUTE_DOMAIN..."
+        echo "The proxy resolves compute hosts with the system resolver, which must point at $DNS_MOCK_ADDR"
+        DNS_MOCK_ADDR="$DNS_MOCK_ADDR" DNS_MOCK_ZONE="$COMPUTE_DOMAIN" RUST_LOG=info ./target/release/dns-mock > logs/dns-mock.log 2>&1 &
+        DNS_MOCK_PID=$!
+        echo "dns-mock started with PID $DNS_MOCK_PID"
+    fi
+
     echo "Starting cplane-mock on port $CPLANE_MOCK_PORT..."
-    PROXY_COMPUTE_ADDR="localhost:5432" RUST_LOG=info ./target/release/cplane-mock > logs/cplane-mock.log 2>&1 &
+    PROXY_COMPUTE_ADDR="localhost:5432" REDIS_NOTIFICATIONS_ADDR="localhost:$REDIS_MOCK_PORT" CPLANE_SCRAM_SALT="$SCRAM_SALT" COMPUTE_DOMAIN="$COMPUTE_DOMAIN" COMPUTE_ADMIN_USER=cloud_admin RUST_LOG=info ./target/release/cplane-mock > logs/cplane-mock.log 2>&1 &
     CPLANE_MOCK_PID=$!
     echo "cplane-mock started with PID $CPLANE_MOCK_PID"
 
@@ -120,7 +146,8 @@ start_bare_metal_services() {
         --http "0.0.0.0:$PROXY_HTTP_PORT" \
         --wss "0.0.0.0:$PROXY_WSS_PORT" \
         --project-info-cache "size=100000,ttl=60m,max_roles=10,gc_interval=60m" \
-        --wake-compute-cache "size=100000,ttl=60m" > logs/proxy.log 2>&1 &
+        --wake-compute-cache "size=100000,ttl=60m" \
+        --redis-notifications "redis://localhost:$REDIS_MOCK_PORT" > logs/proxy.log 2>&1 &
 
     PROXY_PID=$!
     echo "Neon proxy started with PID $PROXY_PID"
@@ -142,6 +169,15 @@ scrape_configs:
   static_configs:
   - targets:
     - localhost:$PROXY_HTTP_PORT
+- job_name: cplane
+  honor_timestamps: true
+  scrape_interval: 15s
+  scrape_timeout: 10s
+  metrics_path: /metrics
+  scheme: http
+  static_configs:
+  - targets:
+    - localhost:$CPLANE_MOCK_PORT
 EOF
 
         # Start Prometheus
@@ -249,6 +285,9 @@ EOF
         PG_CONNECTION_RATE=$PG_CONNECTION_RATE \
         PG_CONNECTING_MAX=$PG_CONNECTING_MAX \
         PG_CONNECTION_MAX=$PG_CONNECTION_MAX \
+        PG_USER=$PG_USER PG_PASSWORD=$PG_PASSWORD \
+        PG_PASSWORD_HACK_PERCENT=$PG_PASSWORD_HACK_PERCENT \
+        PG_FOLLOW_CONSOLE_LINKS=$PG_FOLLOW_CONSOLE_LINKS \
         RUST_LOG=info ./target/release/postgres-bench > logs/postgres-bench-$i.log 2>&1 &
         POSTGRES_BENCH_PIDS[$i]=$!
         echo "postgres-bench $i started with PID ${POSTGRES_BENCH_PIDS[$i]}"
@@ -267,6 +306,10 @@ EOF
     mkdir -p target  # Ensure target directory exists
     echo "$POSTGRES_MOCK_PID" > target/postgres-mock.pid
     echo "$CPLANE_MOCK_PID" > target/cplane-mock.pid
+    echo "$REDIS_MOCK_PID" > target/redis-mock.pid
+    if [ -n "$DNS_MOCK_PID" ]; then
+        echo "$DNS_MOCK_PID" > target/dns-mock.pid
+    fi
     if [ -n "$PROMETHEUS_PID" ]; then
         echo "$PROMETHEUS_PID" > target/prometheus.pid
     fi
@@ -317,6 +360,8 @@ stop_bare_metal_services() {
     echo "Cleaning up any remaining processes..."
     pkill -TERM -f postgres-mock 2>/dev/null || true
     pkill -TERM -f cplane-mock 2>/dev/null || true
+    pkill -TERM -f redis-mock 2>/dev/null || true
+    pkill -TERM -f dns-mock 2>/dev/null || true
     pkill -TERM -f prometheus 2>/dev/null || true
     pkill -TERM -f "grafana server" 2>/dev/null || true
     pkill -TERM -f postgres-bench 2>/dev/null || true
@@ -329,6 +374,8 @@ stop_bare_metal_services() {
     # Force kill any stubborn processes
     pkill -KILL -f postgres-mock 2>/dev/null || true
     pkill -KILL -f cplane-mock 2>/dev/null || true
+    pkill -KILL -f redis-mock 2>/dev/null || true
+    pkill -KILL -f dns-mock 2>/dev/null || true
     pkill -KILL -f prometheus 2>/dev/null || true
     pkill -KILL -f "grafana server" 2>/dev/null || true
     pkill -KILL -f postgres-bench 2>/dev/null || true
@@ -1,3 +1,5 @@
 #!/bin/sh
 mkdir -p target
 openssl req -new -x509 -days 265 -nodes -text -out target/proxy.crt -keyout target/proxy.key -subj "/CN=*.neon" -addext "subjectAltName = DNS:*.neon"
+openssl req -new -x509 -days 265 -nodes -text -out target/cplane.crt -keyout target/cplane.key -subj "/CN=cplane" -addext "subjectAltName = DNS:cplane, DNS:localhost, IP:127.0.0.1"
+openssl req -new -x509 -days 265 -nodes -text -out target/compute.crt -keyout target/compute.key -subj "/CN=postgres" -addext "subjectAltName = DNS:postgres, DNS:localhost, IP:127.0.0.1"